@@ -1,36 +1,34 @@
-use crate::cache::Cache;
+use crate::cache::{Cache, Key, Namespace};
 use crate::llm_response::Project;
 use crate::{Lang, VERBOSE};
 
-pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, String) {
+/// Runs the command in the `sandbox` folder, results are cached by the command and the sources.
+pub fn build_tool(lang: &Lang, sandbox: &str, command_str: &str, cache: &Cache) -> (bool, String) {
     match lang {
         Lang::Rust => {
             println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/lib.rs").unwrap();
-            let dependencies = std::fs::read_to_string("sandbox/Cargo.toml").unwrap();
+            let code = std::fs::read_to_string(format!("{}/src/lib.rs", sandbox)).unwrap();
+            let dependencies = std::fs::read_to_string(format!("{}/Cargo.toml", sandbox)).unwrap();
             let src = format!("{}\n{}", dependencies, code);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
+            let key = Key::digest(&[command_str, &src]);
+            let parsed: (i32, String) = match cache.get(Namespace::Build, &key) {
                 None => {
                     let command_parts = command_str.split(" ").collect::<Vec<&str>>();
                     let args = command_parts[1..].to_vec();
                     let output = std::process::Command::new(command_parts[0])
                         .args(args)
-                        .current_dir("sandbox")
+                        .current_dir(sandbox)
                         .output()
                         .unwrap();
                     let exit_code = output.status.code().unwrap();
                     // let std_out = String::from_utf8(output.stdout).unwrap();
                     let std_err = String::from_utf8(output.stderr).unwrap();
                     let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
+                    cache.set(Namespace::Build, key, &tuple);
+                    tuple
                 }
-                Some(result) => result.to_string(),
+                Some(result) => result,
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
 
             let exit_code = parsed.0;
             let output = parsed.1;
@@ -44,19 +42,21 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
         }
         Lang::Java => {
             println!("Launch: {}", command_str);
-            let code =
-                std::fs::read_to_string("sandbox/src/main/java/com/example/solution/Solution.java")
-                    .unwrap();
-            let test = std::fs::read_to_string(
-                "sandbox/src/test/java/com/example/solution/SolutionTest.java",
-            )
+            let code = std::fs::read_to_string(format!(
+                "{}/src/main/java/com/example/solution/Solution.java",
+                sandbox
+            ))
+            .unwrap();
+            let test = std::fs::read_to_string(format!(
+                "{}/src/test/java/com/example/solution/SolutionTest.java",
+                sandbox
+            ))
             .unwrap();
             let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/pom.xml").unwrap();
+            let dependencies = std::fs::read_to_string(format!("{}/pom.xml", sandbox)).unwrap();
             let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
+            let key = Key::digest(&[command_str, &src]);
+            let parsed: (i32, String) = match cache.get(Namespace::Build, &key) {
                 None => {
                     let command_parts = command_str.split(" ").collect::<Vec<&str>>();
                     let args = command_parts[1..].to_vec();
@@ -68,20 +68,18 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
                     };
                     let output = std::process::Command::new(command)
                         .args(args)
-                        .current_dir("sandbox")
+                        .current_dir(sandbox)
                         .output()
                         .unwrap();
                     let exit_code = output.status.code().unwrap();
                     // let std_out = String::from_utf8(output.stdout).unwrap();
                     let std_err = String::from_utf8(output.stderr).unwrap();
                     let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
+                    cache.set(Namespace::Build, key, &tuple);
+                    tuple
                 }
-                Some(result) => result.to_string(),
+                Some(result) => result,
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
 
             let exit_code = parsed.0;
             let output = parsed.1;
@@ -96,15 +94,17 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
 
         Lang::Scala => {
             println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/main/scala/Solution.scala").unwrap();
+            let code =
+                std::fs::read_to_string(format!("{}/src/main/scala/Solution.scala", sandbox))
+                    .unwrap();
             let test =
-                std::fs::read_to_string("sandbox/src/test/scala/SolutionTest.scala").unwrap();
+                std::fs::read_to_string(format!("{}/src/test/scala/SolutionTest.scala", sandbox))
+                    .unwrap();
             let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/build.sbt").unwrap();
+            let dependencies = std::fs::read_to_string(format!("{}/build.sbt", sandbox)).unwrap();
             let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
+            let key = Key::digest(&[command_str, &src]);
+            let parsed: (i32, String) = match cache.get(Namespace::Build, &key) {
                 None => {
                     let command_parts = command_str.split(" ").collect::<Vec<&str>>();
                     let args = command_parts[1..].to_vec();
@@ -116,20 +116,18 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
                     };
                     let output = std::process::Command::new(command)
                         .args(args)
-                        .current_dir("sandbox")
+                        .current_dir(sandbox)
                         .output()
                         .unwrap();
                     let exit_code = output.status.code().unwrap();
                     // let std_out = String::from_utf8(output.stdout).unwrap();
                     let std_err = String::from_utf8(output.stderr).unwrap();
                     let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
+                    cache.set(Namespace::Build, key, &tuple);
+                    tuple
                 }
-                Some(result) => result.to_string(),
+                Some(result) => result,
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
 
             let exit_code = parsed.0;
             let output = parsed.1;
@@ -143,15 +141,20 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
         }
         Lang::Swift => {
             println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/Sources/Solution/Solution.swift").unwrap();
-            let test =
-                std::fs::read_to_string("sandbox/Tests/SolutionTests/SolutionTests.swift").unwrap();
+            let code =
+                std::fs::read_to_string(format!("{}/Sources/Solution/Solution.swift", sandbox))
+                    .unwrap();
+            let test = std::fs::read_to_string(format!(
+                "{}/Tests/SolutionTests/SolutionTests.swift",
+                sandbox
+            ))
+            .unwrap();
             let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/Package.swift").unwrap();
+            let dependencies =
+                std::fs::read_to_string(format!("{}/Package.swift", sandbox)).unwrap();
             let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
+            let key = Key::digest(&[command_str, &src]);
+            let parsed: (i32, String) = match cache.get(Namespace::Build, &key) {
                 None => {
                     let command_parts = command_str.split(" ").collect::<Vec<&str>>();
                     let args = command_parts[1..].to_vec();
@@ -159,20 +162,18 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
                     let command = command_parts[0].to_string();
                     let output = std::process::Command::new(command)
                         .args(args)
-                        .current_dir("sandbox")
+                        .current_dir(sandbox)
                         .output()
                         .unwrap();
                     let exit_code = output.status.code().unwrap();
                     // let std_out = String::from_utf8(output.stdout).unwrap();
                     let std_err = String::from_utf8(output.stderr).unwrap();
                     let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
+                    cache.set(Namespace::Build, key, &tuple);
+                    tuple
                 }
-                Some(result) => result.to_string(),
+                Some(result) => result,
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
 
             let exit_code = parsed.0;
             let output = parsed.1;
@@ -186,14 +187,17 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
         }
         Lang::Kotlin => {
             println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/main/kotlin/Solution.kt").unwrap();
-            let test = std::fs::read_to_string("sandbox/src/test/kotlin/SolutionTest.kt").unwrap();
+            let code = std::fs::read_to_string(format!("{}/src/main/kotlin/Solution.kt", sandbox))
+                .unwrap();
+            let test =
+                std::fs::read_to_string(format!("{}/src/test/kotlin/SolutionTest.kt", sandbox))
+                    .unwrap();
             let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/build.gradle").unwrap();
+            let dependencies =
+                std::fs::read_to_string(format!("{}/build.gradle", sandbox)).unwrap();
             let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
+            let key = Key::digest(&[command_str, &src]);
+            let parsed: (i32, String) = match cache.get(Namespace::Build, &key) {
                 None => {
                     let command_parts = command_str.split(" ").collect::<Vec<&str>>();
                     let args = command_parts[1..].to_vec();
@@ -206,20 +210,18 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
                     // println!("{}, {:?}", command, args);
                     let output = std::process::Command::new(command)
                         .args(args)
-                        .current_dir("sandbox")
+                        .current_dir(sandbox)
                         .output()
                         .unwrap();
                     let exit_code = output.status.code().unwrap();
                     // let std_out = String::from_utf8(output.stdout).unwrap();
                     let std_err = String::from_utf8(output.stderr).unwrap();
                     let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
+                    cache.set(Namespace::Build, key, &tuple);
+                    tuple
                 }
-                Some(result) => result.to_string(),
+                Some(result) => result,
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
 
             let exit_code = parsed.0;
             let output = parsed.1;
@@ -237,33 +239,31 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
             if command_str == "" {
                 return (true, "".to_string());
             }
-            let code = std::fs::read_to_string("sandbox/solution.py").unwrap();
-            let test = std::fs::read_to_string("sandbox/test.py").unwrap();
+            let code = std::fs::read_to_string(format!("{}/solution.py", sandbox)).unwrap();
+            let test = std::fs::read_to_string(format!("{}/test.py", sandbox)).unwrap();
             let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/requirements.txt").unwrap();
+            let dependencies =
+                std::fs::read_to_string(format!("{}/requirements.txt", sandbox)).unwrap();
             let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
+            let key = Key::digest(&[command_str, &src]);
+            let parsed: (i32, String) = match cache.get(Namespace::Build, &key) {
                 None => {
                     let command_parts = command_str.split(" ").collect::<Vec<&str>>();
                     let args = command_parts[1..].to_vec();
                     let output = std::process::Command::new(command_parts[0])
                         .args(args)
-                        .current_dir("sandbox")
+                        .current_dir(sandbox)
                         .output()
                         .unwrap();
                     let exit_code = output.status.code().unwrap();
                     // let std_out = String::from_utf8(output.stdout).unwrap();
                     let std_err = String::from_utf8(output.stderr).unwrap();
                     let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
+                    cache.set(Namespace::Build, key, &tuple);
+                    tuple
                 }
-                Some(result) => result.to_string(),
+                Some(result) => result,
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
 
             let exit_code = parsed.0;
             let output = parsed.1;
@@ -278,14 +278,15 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
 
         Lang::JavaScript => {
             println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/solution.js").unwrap();
-            let test = std::fs::read_to_string("sandbox/src/solution.test.js").unwrap();
+            let code = std::fs::read_to_string(format!("{}/src/solution.js", sandbox)).unwrap();
+            let test =
+                std::fs::read_to_string(format!("{}/src/solution.test.js", sandbox)).unwrap();
             let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/package.json").unwrap();
+            let dependencies =
+                std::fs::read_to_string(format!("{}/package.json", sandbox)).unwrap();
             let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
+            let key = Key::digest(&[command_str, &src]);
+            let parsed: (i32, String) = match cache.get(Namespace::Build, &key) {
                 None => {
                     let command_parts = command_str.split(" ").collect::<Vec<&str>>();
                     let args = command_parts[1..].to_vec();
@@ -297,20 +298,18 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
                     };
                     let output = std::process::Command::new(command)
                         .args(args)
-                        .current_dir("sandbox")
+                        .current_dir(sandbox)
                         .output()
                         .unwrap();
                     let exit_code = output.status.code().unwrap();
                     // let std_out = String::from_utf8(output.stdout).unwrap();
                     let std_err = String::from_utf8(output.stderr).unwrap();
                     let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
+                    cache.set(Namespace::Build, key, &tuple);
+                    tuple
                 }
-                Some(result) => result.to_string(),
+                Some(result) => result,
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
 
             let exit_code = parsed.0;
             let output = parsed.1;
@@ -324,15 +323,16 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
         }
         Lang::TypeScript => {
             println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/solution.ts").unwrap();
-            let test = std::fs::read_to_string("sandbox/src/solution.test.ts").unwrap();
-            let config = std::fs::read_to_string("sandbox/tsconfig.json").unwrap();
+            let code = std::fs::read_to_string(format!("{}/src/solution.ts", sandbox)).unwrap();
+            let test =
+                std::fs::read_to_string(format!("{}/src/solution.test.ts", sandbox)).unwrap();
+            let config = std::fs::read_to_string(format!("{}/tsconfig.json", sandbox)).unwrap();
             let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/package.json").unwrap();
+            let dependencies =
+                std::fs::read_to_string(format!("{}/package.json", sandbox)).unwrap();
             let src = format!("{}\n{}\n{}", dependencies, config, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
+            let key = Key::digest(&[command_str, &src]);
+            let parsed: (i32, String) = match cache.get(Namespace::Build, &key) {
                 None => {
                     let command_parts = command_str.split(" ").collect::<Vec<&str>>();
                     let args = command_parts[1..].to_vec();
@@ -344,20 +344,18 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
                     };
                     let output = std::process::Command::new(command)
                         .args(args)
-                        .current_dir("sandbox")
+                        .current_dir(sandbox)
                         .output()
                         .unwrap();
                     let exit_code = output.status.code().unwrap();
                     // let std_out = String::from_utf8(output.stdout).unwrap();
                     let std_err = String::from_utf8(output.stderr).unwrap();
                     let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
+                    cache.set(Namespace::Build, key, &tuple);
+                    tuple
                 }
-                Some(result) => result.to_string(),
+                Some(result) => result,
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
 
             let exit_code = parsed.0;
             let output = parsed.1;
@@ -371,14 +369,15 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
         }
         Lang::Php => {
             println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/Solution.php").unwrap();
-            let test = std::fs::read_to_string("sandbox/tests/SolutionTest.php").unwrap();
+            let code = std::fs::read_to_string(format!("{}/src/Solution.php", sandbox)).unwrap();
+            let test =
+                std::fs::read_to_string(format!("{}/tests/SolutionTest.php", sandbox)).unwrap();
             let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/composer.json").unwrap();
+            let dependencies =
+                std::fs::read_to_string(format!("{}/composer.json", sandbox)).unwrap();
             let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
+            let key = Key::digest(&[command_str, &src]);
+            let parsed: (i32, String) = match cache.get(Namespace::Build, &key) {
                 None => {
                     let command_parts = command_str.split(" ").collect::<Vec<&str>>();
                     let args = command_parts[1..].to_vec();
@@ -392,7 +391,7 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
 
                     let output = std::process::Command::new(&command)
                         .args(&args)
-                        .current_dir("sandbox")
+                        .current_dir(sandbox)
                         .output()
                         .unwrap();
 
@@ -400,13 +399,11 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
                     // let std_out = String::from_utf8(output.stdout).unwrap();
                     let std_err = String::from_utf8(output.stderr).unwrap();
                     let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
+                    cache.set(Namespace::Build, key, &tuple);
+                    tuple
                 }
-                Some(result) => result.to_string(),
+                Some(result) => result,
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
 
             let exit_code = parsed.0;
             let output = parsed.1;
@@ -422,12 +419,12 @@ pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, S
     }
 }
 
-pub fn create_project_rust(lang: &Lang, project: &Project) {
+pub fn create_project_rust(lang: &Lang, sandbox: &str, project: &Project) {
     match lang {
         Lang::Rust => {
-            println!("Create sandbox project with");
+            println!("Create {} project with", sandbox);
             println!("{}\n{}", project.dependencies, project.solution_code);
-            let sandbox_path = "sandbox";
+            let sandbox_path = sandbox;
             let src_path = format!("{}/src", sandbox_path);
             let main_path = format!("{}/src/lib.rs", sandbox_path);
             let cargo_path = format!("{}/Cargo.toml", sandbox_path);
@@ -446,13 +443,13 @@ pub fn create_project_rust(lang: &Lang, project: &Project) {
         _ => panic!("Unsupported language: {:?}", lang),
     }
 }
-pub fn create_project_java(project: &Project) {
-    println!("Create sandbox project with");
+pub fn create_project_java(sandbox: &str, project: &Project) {
+    println!("Create {} project with", sandbox);
     println!(
         "{}\n{}\n{}",
         project.dependencies, project.solution_code, project.test_code
     );
-    let sandbox_path = "sandbox";
+    let sandbox_path = sandbox;
 
     let main_path = format!(
         "{}/src/main/java/com/example/solution/Solution.java",
@@ -483,13 +480,13 @@ pub fn create_project_java(project: &Project) {
     std::fs::write(&test_path, &project.test_code).unwrap();
     std::fs::write(&pom_path, &project.dependencies).unwrap();
 }
-pub fn create_project_scala(project: &Project) {
-    println!("Create sandbox project with");
+pub fn create_project_scala(sandbox: &str, project: &Project) {
+    println!("Create {} project with", sandbox);
     println!(
         "{}\n{}\n{}",
         project.dependencies, project.solution_code, project.test_code
     );
-    let sandbox_path = "sandbox";
+    let sandbox_path = sandbox;
 
     let main_path = format!("{}/src/main/scala/Solution.scala", sandbox_path);
     let test_path = format!("{}/src/test/scala/SolutionTest.scala", sandbox_path);
@@ -506,13 +503,13 @@ pub fn create_project_scala(project: &Project) {
     std::fs::write(&test_path, &project.test_code).unwrap();
     std::fs::write(&pom_path, &project.dependencies).unwrap();
 }
-pub fn create_project_swift(project: &Project) {
-    println!("Create sandbox project with");
+pub fn create_project_swift(sandbox: &str, project: &Project) {
+    println!("Create {} project with", sandbox);
     println!(
         "{}\n{}\n{}",
         project.dependencies, project.solution_code, project.test_code
     );
-    let sandbox_path = "sandbox";
+    let sandbox_path = sandbox;
 
     let main_path = format!("{}/Sources/Solution/Solution.swift", sandbox_path);
     let test_path = format!("{}/Tests/SolutionTests/SolutionTests.swift", sandbox_path);
@@ -529,13 +526,13 @@ pub fn create_project_swift(project: &Project) {
     std::fs::write(&test_path, &project.test_code).unwrap();
     std::fs::write(&pom_path, &project.dependencies).unwrap();
 }
-pub fn create_project_kotlin(project: &Project) {
-    println!("Create sandbox project with");
+pub fn create_project_kotlin(sandbox: &str, project: &Project) {
+    println!("Create {} project with", sandbox);
     println!(
         "{}\n{}\n{}",
         project.dependencies, project.solution_code, project.test_code
     );
-    let sandbox_path = "sandbox";
+    let sandbox_path = sandbox;
 
     let main_path = format!("{}/src/main/kotlin/Solution.kt", sandbox_path);
     let test_path = format!("{}/src/test/kotlin/SolutionTest.kt", sandbox_path);
@@ -552,13 +549,13 @@ pub fn create_project_kotlin(project: &Project) {
     std::fs::write(&test_path, &project.test_code).unwrap();
     std::fs::write(&pom_path, &project.dependencies).unwrap();
 }
-pub fn create_project_python(project: &Project) {
-    println!("Create sandbox project with");
+pub fn create_project_python(sandbox: &str, project: &Project) {
+    println!("Create {} project with", sandbox);
     println!(
         "{}\n{}\n{}",
         project.dependencies, project.solution_code, project.test_code
     );
-    let sandbox_path = "sandbox";
+    let sandbox_path = sandbox;
 
     let main_path = format!("{}/solution.py", sandbox_path);
     let test_path = format!("{}/test.py", sandbox_path);
@@ -574,13 +571,13 @@ pub fn create_project_python(project: &Project) {
     std::fs::write(&pom_path, &project.dependencies).unwrap();
 }
 
-pub fn create_project_javascript(project: &Project) {
-    println!("Create sandbox project with");
+pub fn create_project_javascript(sandbox: &str, project: &Project) {
+    println!("Create {} project with", sandbox);
     println!(
         "{}\n{}\n{}",
         project.dependencies, project.solution_code, project.test_code
     );
-    let sandbox_path = "sandbox";
+    let sandbox_path = sandbox;
 
     let main_path = format!("{}/src/solution.js", sandbox_path);
     let test_path = format!("{}/src/solution.test.js", sandbox_path);
@@ -597,8 +594,8 @@ pub fn create_project_javascript(project: &Project) {
     std::fs::write(&pom_path, &project.dependencies).unwrap();
 }
 
-pub fn create_project_typescript(project: &Project) {
-    println!("Create sandbox project with");
+pub fn create_project_typescript(sandbox: &str, project: &Project) {
+    println!("Create {} project with", sandbox);
     println!(
         "{}\n{}\n{}\n{}",
         project.dependencies,
@@ -606,7 +603,7 @@ pub fn create_project_typescript(project: &Project) {
         project.solution_code,
         project.test_code
     );
-    let sandbox_path = "sandbox";
+    let sandbox_path = sandbox;
 
     let main_path = format!("{}/src/solution.ts", sandbox_path);
     let test_path = format!("{}/src/solution.test.ts", sandbox_path);
@@ -625,13 +622,13 @@ pub fn create_project_typescript(project: &Project) {
     std::fs::write(&config_path, &project.additional_config[0]).unwrap();
 }
 
-pub fn create_project_php(project: &Project) {
-    println!("Create sandbox project with");
+pub fn create_project_php(sandbox: &str, project: &Project) {
+    println!("Create {} project with", sandbox);
     println!(
         "{}\n{}\n{}",
         project.dependencies, project.solution_code, project.test_code
     );
-    let sandbox_path = "sandbox";
+    let sandbox_path = sandbox;
     let main_path = format!("{}/src/Solution.php", sandbox_path);
     let test_path = format!("{}/tests/SolutionTest.php", sandbox_path);
     let pom_path = format!("{}/composer.json", sandbox_path);
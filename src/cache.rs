@@ -1,55 +1,962 @@
 use bincode::{config, Decode, Encode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::fs;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Encode, Decode, PartialEq, Debug)]
+/// Kind of cached results, each one is kept apart from the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Namespace {
+    /// Answers of the chat models.
+    Llm,
+    /// Exit codes and output of build and test commands.
+    Build,
+    /// Embedding vectors.
+    Embedding,
+}
+
+impl Namespace {
+    pub const ALL: [Namespace; 3] = [Namespace::Llm, Namespace::Build, Namespace::Embedding];
+
+    /// Inverse of `name`.
+    pub fn parse(name: &str) -> Option<Namespace> {
+        Namespace::ALL
+            .into_iter()
+            .find(|namespace| namespace.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Namespace::Llm => "llm",
+            Namespace::Build => "build",
+            Namespace::Embedding => "embedding",
+        }
+    }
+}
+
+impl Display for Namespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// SHA-256 of the inputs of a cached result, the same on every platform and release.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key(String);
+
+impl Key {
+    /// Every part is prefixed with its length, so ["ab", "c"] and ["a", "bc"] differ.
+    pub fn digest(parts: &[&str]) -> Key {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        let hash = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Key(hash)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(&self.0)
+    }
+}
+
+/// Cache file in the working folder.
+pub const CACHE_FILE: &str = "cache.bin";
+
+// Start of a journal, files of older releases hold other formats without it
+const MAGIC: &[u8; 8] = b"RSNJRNL2";
+// Length and checksum in front of every record
+const HEADER_LEN: usize = 12;
+// Overwritten and removed records are dropped once they outnumber the live entries
+const MIN_COMPACT_RECORDS: usize = 1000;
+// Namespace of the records of `Cache::set_meta`, older releases skip them
+const META: &str = "meta";
+
+/// Limits of a cache, the least recently used entries are evicted first. Nothing is
+/// limited by default.
+#[derive(Debug, Default, Clone)]
+pub struct Limits {
+    /// Bytes of all keys and values together.
+    pub max_size: Option<u64>,
+    pub max_entries: HashMap<Namespace, usize>,
+    /// Entries older than this are neither returned nor kept.
+    pub ttl: HashMap<Namespace, Duration>,
+}
+
+/// Entries and bytes of keys and values of one namespace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub namespace: Namespace,
+    pub entries: usize,
+    pub size: u64,
+}
+
+/// Cached entry without its value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryInfo {
+    pub namespace: Namespace,
+    pub key: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub used: u64,
+    /// Beginning of the value as JSON.
+    pub preview: String,
+}
+
+/// State of a cache file, as `verify` found it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Verification {
+    pub records: usize,
+    pub entries: usize,
+    /// Bytes after the last valid record, a crash in the middle of a write leaves them.
+    pub damaged_bytes: usize,
+    /// Entries whose value is not valid JSON.
+    pub invalid_values: usize,
+    /// The file was written by an older release and is not read at all.
+    pub outdated: bool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    // JSON of the cached value
+    value: String,
+    // Seconds since the Unix epoch
+    created: u64,
+    used: u64,
+}
+
+// Changes of the cache in the order they were made
+#[derive(Debug, Encode, Decode)]
+enum Record {
+    Set {
+        namespace: String,
+        key: String,
+        value: String,
+        created: u64,
+        used: u64,
+    },
+    Touch {
+        namespace: String,
+        key: String,
+        used: u64,
+    },
+    Remove {
+        namespace: String,
+        key: String,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    entries: HashMap<Namespace, HashMap<String, Entry>>,
+    // Values of `Cache::set_meta` as JSON, apart from the limited namespaces
+    meta: HashMap<String, String>,
+    // None when the cache lives in memory only or its file can't be written
+    journal: Option<File>,
+    // Records in the journal, overwritten and removed ones included
+    records: usize,
+    // Bytes of all keys and values
+    size: u64,
+    // Start of the session, entries used before it are touched in the journal once
+    opened: u64,
+}
+
+impl Store {
+    fn len(&self) -> usize {
+        self.entries
+            .values()
+            .map(|entries| entries.len())
+            .sum::<usize>()
+            + self.meta.len()
+    }
+
+    fn insert(&mut self, namespace: Namespace, key: String, entry: Entry) {
+        let key_len = key.len() as u64;
+        self.size += key_len + entry.value.len() as u64;
+        let old = self
+            .entries
+            .entry(namespace)
+            .or_default()
+            .insert(key, entry);
+        if let Some(old) = old {
+            self.size -= key_len + old.value.len() as u64;
+        }
+    }
+
+    fn remove(&mut self, namespace: Namespace, key: &str) {
+        let entries = self.entries.entry(namespace).or_default();
+        if let Some(old) = entries.remove(key) {
+            self.size -= (key.len() + old.value.len()) as u64;
+        }
+    }
+
+    // Records of unknown namespaces come from newer releases and are skipped
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Set {
+                namespace,
+                key,
+                value,
+                created,
+                used,
+            } => {
+                if namespace == META {
+                    self.meta.insert(key, value);
+                } else if let Some(namespace) = Namespace::parse(&namespace) {
+                    let entry = Entry {
+                        value,
+                        created,
+                        used,
+                    };
+                    self.insert(namespace, key, entry);
+                }
+            }
+            Record::Touch {
+                namespace,
+                key,
+                used,
+            } => {
+                let entry = Namespace::parse(&namespace)
+                    .and_then(|namespace| self.entries.get_mut(&namespace))
+                    .and_then(|entries| entries.get_mut(&key));
+                if let Some(entry) = entry {
+                    entry.used = used;
+                }
+            }
+            Record::Remove { namespace, key } => {
+                if let Some(namespace) = Namespace::parse(&namespace) {
+                    self.remove(namespace, &key);
+                }
+            }
+        }
+    }
+}
+
+/// Shared by all threads of a session, so it locks itself.
+///
+/// Every change appends one record to the journal file, which is rewritten with the
+/// live entries only when most of its records are outdated.
+#[derive(Debug)]
 pub struct Cache {
-    cache: HashMap<String, String>,
+    store: Mutex<Store>,
+    // None keeps the cache in memory only
+    file: Option<String>,
+    limits: Limits,
 }
 
 impl Cache {
     pub fn new() -> Cache {
-        let mut cache = Cache {
-            cache: HashMap::new(),
+        Cache::open(CACHE_FILE)
+    }
+
+    pub fn open(file: &str) -> Cache {
+        let cache = Cache {
+            store: Mutex::new(Store::default()),
+            file: Some(file.to_string()),
+            limits: Limits::default(),
         };
         cache.restore();
         cache
     }
 
-    pub fn get(&mut self, key: &str) -> Option<&String> {
-        self.cache.get(key)
+    /// Cache which is neither read from nor written to disk, for tests and recording.
+    pub fn in_memory() -> Cache {
+        Cache {
+            store: Mutex::new(Store::default()),
+            file: None,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Evicts what is over the limits at once and after every `set`.
+    pub fn with_limits(mut self, limits: Limits) -> Cache {
+        self.limits = limits;
+        let mut store = self.store.lock().unwrap();
+        self.evict(&mut store, now(), None);
+        drop(store);
+        self
+    }
+
+    /// None when the entry is missing, expired or was stored with another type.
+    pub fn get<T: DeserializeOwned>(&self, namespace: Namespace, key: &Key) -> Option<T> {
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        let opened = store.opened;
+        let entry = store.entries.get_mut(&namespace)?.get_mut(key.as_str())?;
+        if self.expired(namespace, entry, now) {
+            return None;
+        }
+        let touch = entry.used < opened;
+        entry.used = now;
+        let value = serde_json::from_str(&entry.value).ok();
+        if touch {
+            let record = Record::Touch {
+                namespace: namespace.name().to_string(),
+                key: key.to_string(),
+                used: now,
+            };
+            self.append(&mut store, &record);
+        }
+        value
+    }
+
+    pub fn set<T: Serialize + ?Sized>(&self, namespace: Namespace, key: Key, value: &T) {
+        let value = serde_json::to_string(value).unwrap();
+        let now = now();
+        // Written under the lock, so records of concurrent writers don't interleave
+        let mut store = self.store.lock().unwrap();
+        let record = Record::Set {
+            namespace: namespace.name().to_string(),
+            key: key.0.clone(),
+            value,
+            created: now,
+            used: now,
+        };
+        self.append(&mut store, &record);
+        store.apply(record);
+        if self.over_limits(&store, namespace) {
+            self.evict(&mut store, now, Some((namespace, key.as_str())));
+        }
+        if store.records >= MIN_COMPACT_RECORDS && store.records > 2 * store.len() {
+            self.save(&mut store);
+        }
     }
 
-    pub fn set(&mut self, key: String, value: String) {
-        self.cache.insert(key, value);
-        self.save();
+    /// Value which describes other entries, like the dimension of cached embeddings. It
+    /// is not in any namespace, so it is never evicted, purged or listed.
+    pub fn get_meta<T: DeserializeOwned>(&self, key: &Key) -> Option<T> {
+        let store = self.store.lock().unwrap();
+        serde_json::from_str(store.meta.get(key.as_str())?).ok()
     }
 
-    fn save(&mut self) {
-        let config = config::standard();
-        let encoded: Vec<u8> = bincode::encode_to_vec(&*self, config).unwrap();
-        fs::write("cache.bin", encoded).unwrap();
+    pub fn set_meta<T: Serialize + ?Sized>(&self, key: Key, value: &T) {
+        let mut store = self.store.lock().unwrap();
+        let record = Record::Set {
+            namespace: META.to_string(),
+            key: key.0,
+            value: serde_json::to_string(value).unwrap(),
+            // Neither expires nor ages
+            created: 0,
+            used: 0,
+        };
+        self.append(&mut store, &record);
+        store.apply(record);
     }
 
-    fn restore(&mut self) {
-        if !std::path::Path::new("cache.bin").exists() {
-            return;
+    /// Removes the entries of `namespace`, or of all namespaces, which were created more
+    /// than `older_than` ago, or all of them. Returns the number of removed entries.
+    pub fn purge(&self, namespace: Option<Namespace>, older_than: Option<Duration>) -> usize {
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        let mut removed = vec![];
+        for (entry_namespace, entries) in &store.entries {
+            if namespace.is_some_and(|namespace| namespace != *entry_namespace) {
+                continue;
+            }
+            for (key, entry) in entries {
+                if older_than.is_none_or(|age| now.saturating_sub(entry.created) >= age.as_secs()) {
+                    removed.push((*entry_namespace, key.clone()));
+                }
+            }
+        }
+        self.remove(&mut store, &removed);
+        if !removed.is_empty() {
+            self.save(&mut store);
+        }
+        removed.len()
+    }
+
+    pub fn stats(&self) -> Vec<Stats> {
+        let store = self.store.lock().unwrap();
+        Namespace::ALL
+            .into_iter()
+            .map(|namespace| {
+                let entries = store.entries.get(&namespace);
+                Stats {
+                    namespace,
+                    entries: entries.map_or(0, |entries| entries.len()),
+                    size: entries.map_or(0, |entries| {
+                        entries
+                            .iter()
+                            .map(|(key, entry)| (key.len() + entry.value.len()) as u64)
+                            .sum()
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    /// Entries of `namespace` or of all namespaces, the most recently used first.
+    pub fn list(&self, namespace: Option<Namespace>) -> Vec<EntryInfo> {
+        let store = self.store.lock().unwrap();
+        let mut list = store
+            .entries
+            .iter()
+            .filter(|(entry_namespace, _)| namespace.is_none_or(|n| n == **entry_namespace))
+            .flat_map(|(namespace, entries)| {
+                entries
+                    .iter()
+                    .map(|(key, entry)| entry_info(*namespace, key, entry))
+            })
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| b.used.cmp(&a.used).then_with(|| a.key.cmp(&b.key)));
+        list
+    }
+
+    /// Entry and its value as JSON. `key` may be the beginning of a key, like a git hash.
+    pub fn show(&self, key: &str) -> Result<(EntryInfo, String), String> {
+        let store = self.store.lock().unwrap();
+        let mut found = store.entries.iter().flat_map(|(namespace, entries)| {
+            entries
+                .iter()
+                .filter(|(entry_key, _)| entry_key.starts_with(key))
+                .map(|(entry_key, entry)| (*namespace, entry_key, entry))
+        });
+        match (found.next(), found.count()) {
+            (Some((namespace, entry_key, entry)), 0) => {
+                Ok((entry_info(namespace, entry_key, entry), entry.value.clone()))
+            }
+            (Some(_), more) => Err(format!(
+                "{} entries have a key starting with {}",
+                more + 1,
+                key
+            )),
+            (None, _) => Err(format!("No entry with key {}", key)),
+        }
+    }
+
+    fn expired(&self, namespace: Namespace, entry: &Entry, now: u64) -> bool {
+        self.limits
+            .ttl
+            .get(&namespace)
+            .is_some_and(|ttl| now.saturating_sub(entry.created) >= ttl.as_secs())
+    }
+
+    fn over_limits(&self, store: &Store, namespace: Namespace) -> bool {
+        let entries = store
+            .entries
+            .get(&namespace)
+            .map_or(0, |entries| entries.len());
+        self.limits
+            .max_entries
+            .get(&namespace)
+            .is_some_and(|max| entries > *max)
+            || self.limits.max_size.is_some_and(|max| store.size > max)
+    }
+
+    // Drops expired entries, then the least recently used ones down to nine tenths of
+    // the limits, so that the next inserts don't evict again at once. Entries used in the
+    // same second are in no order, the `kept` one which was just set goes last and is
+    // never evicted.
+    fn evict(&self, store: &mut Store, now: u64, kept: Option<(Namespace, &str)>) {
+        let rank = |namespace: Namespace, key: &str, entry: &Entry| {
+            (kept == Some((namespace, key)), entry.used)
+        };
+        let mut removed = vec![];
+        for (namespace, entries) in &store.entries {
+            let mut live = vec![];
+            for (key, entry) in entries {
+                if self.expired(*namespace, entry, now) {
+                    removed.push((*namespace, key.clone()));
+                } else {
+                    live.push((rank(*namespace, key, entry), key));
+                }
+            }
+            if let Some(max) = self.limits.max_entries.get(namespace) {
+                if live.len() > *max {
+                    live.sort();
+                    let excess = live.len() - target(*max as u64) as usize;
+                    removed.extend(
+                        live[..excess]
+                            .iter()
+                            .map(|(_, key)| (*namespace, key.to_string())),
+                    );
+                }
+            }
+        }
+        self.remove(store, &removed);
+
+        if let Some(max_size) = self.limits.max_size {
+            if store.size > max_size {
+                let mut live = store
+                    .entries
+                    .iter()
+                    .flat_map(|(namespace, entries)| {
+                        entries.iter().map(|(key, entry)| {
+                            (
+                                rank(*namespace, key, entry),
+                                *namespace,
+                                key,
+                                (key.len() + entry.value.len()) as u64,
+                            )
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                live.sort();
+                let mut size = store.size;
+                let mut removed = vec![];
+                for ((is_kept, _), namespace, key, entry_size) in live {
+                    if size <= target(max_size) || is_kept {
+                        break;
+                    }
+                    size -= entry_size;
+                    removed.push((namespace, key.clone()));
+                }
+                self.remove(store, &removed);
+            }
+        }
+    }
+
+    fn remove(&self, store: &mut Store, removed: &[(Namespace, String)]) {
+        for (namespace, key) in removed {
+            let record = Record::Remove {
+                namespace: namespace.name().to_string(),
+                key: key.clone(),
+            };
+            self.append(store, &record);
+            store.apply(record);
+        }
+    }
+
+    fn append(&self, store: &mut Store, record: &Record) {
+        let journal = match store.journal.as_mut() {
+            Some(journal) => journal,
+            None => return,
+        };
+        match journal.write_all(&frame(record)) {
+            Ok(()) => store.records += 1,
+            Err(err) => {
+                println!("Cache is not saved any more: {}", err);
+                store.journal = None;
+            }
+        }
+    }
+
+    fn save(&self, store: &mut Store) {
+        if let Err(err) = self.compact(store) {
+            println!("Cache is not saved any more: {}", err);
+            store.journal = None;
+        }
+    }
+
+    // The live entries go to a new file which replaces the journal at once, so a crash
+    // leaves either the old or the new journal complete
+    fn compact(&self, store: &mut Store) -> std::io::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let temp = format!("{}.tmp", file);
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        writer.write_all(MAGIC)?;
+        for (namespace, entries) in &store.entries {
+            for (key, entry) in entries {
+                let record = Record::Set {
+                    namespace: namespace.name().to_string(),
+                    key: key.clone(),
+                    value: entry.value.clone(),
+                    created: entry.created,
+                    used: entry.used,
+                };
+                writer.write_all(&frame(&record))?;
+            }
+        }
+        for (key, value) in &store.meta {
+            let record = Record::Set {
+                namespace: META.to_string(),
+                key: key.clone(),
+                value: value.clone(),
+                created: 0,
+                used: 0,
+            };
+            writer.write_all(&frame(&record))?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp, file)?;
+        store.journal = Some(OpenOptions::new().append(true).open(file)?);
+        store.records = store.len();
+        Ok(())
+    }
+
+    fn restore(&self) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let mut store = self.store.lock().unwrap();
+        store.opened = now();
+        let complete = match fs::read(file) {
+            Ok(data) if data.starts_with(MAGIC) => {
+                let data = &data[MAGIC.len()..];
+                let (records, read) = read_journal(data);
+                let complete = read == data.len();
+                store.records = records.len();
+                for record in records {
+                    store.apply(record);
+                }
+                if !complete {
+                    println!(
+                        "{} is damaged after {} records, the rest is dropped",
+                        file, store.records
+                    );
+                }
+                complete
+            }
+            // Kept for a downgrade, the new journal would overwrite it
+            Ok(_) => {
+                let old = format!("{}.old", file);
+                if let Err(err) = fs::rename(file, &old) {
+                    println!(
+                        "{} has an outdated format and can't be moved to {}, it is not saved: {}",
+                        file, old, err
+                    );
+                    return;
+                }
+                println!(
+                    "{} has an outdated format, it is kept as {} and the cache starts empty",
+                    file, old
+                );
+                false
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => false,
+            Err(err) => {
+                println!("Cache {} is not readable, it is not saved: {}", file, err);
+                return;
+            }
+        };
+        // A damaged tail is cut off before anything is appended after it
+        let result = if !complete || store.records > 2 * store.len() {
+            self.compact(&mut store)
+        } else {
+            OpenOptions::new()
+                .append(true)
+                .open(file)
+                .map(|journal| store.journal = Some(journal))
+        };
+        if let Err(err) = result {
+            println!("Cache is not saved any more: {}", err);
         }
-        let config = config::standard();
-        let encoded = fs::read("cache.bin").unwrap();
-        let (decoded, _): (Cache, usize) = bincode::decode_from_slice(&encoded, config).unwrap();
-        *self = decoded;
     }
 }
 
+/// Reads `file` like `Cache::open`, without repairing it.
+pub fn verify(file: &str) -> std::io::Result<Verification> {
+    let data = fs::read(file)?;
+    let data = match data.strip_prefix(MAGIC) {
+        Some(data) => data,
+        None => {
+            return Ok(Verification {
+                outdated: true,
+                ..Verification::default()
+            })
+        }
+    };
+    let (records, read) = read_journal(data);
+    let mut store = Store::default();
+    let verification = Verification {
+        records: records.len(),
+        damaged_bytes: data.len() - read,
+        ..Verification::default()
+    };
+    for record in records {
+        store.apply(record);
+    }
+    let invalid_values = store
+        .entries
+        .values()
+        .flat_map(|entries| entries.values())
+        .filter(|entry| serde_json::from_str::<serde_json::Value>(&entry.value).is_err())
+        .count();
+    Ok(Verification {
+        entries: store.len(),
+        invalid_values,
+        ..verification
+    })
+}
+
+// Nine tenths of a limit, rounded down but at least one
+fn target(max: u64) -> u64 {
+    (max - max / 10).max(1)
+}
+
+fn entry_info(namespace: Namespace, key: &str, entry: &Entry) -> EntryInfo {
+    EntryInfo {
+        namespace,
+        key: key.to_string(),
+        size: (key.len() + entry.value.len()) as u64,
+        created: entry.created,
+        used: entry.used,
+        preview: entry.value.chars().take(60).collect(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// Length, the first bytes of the SHA-256 and the encoded record
+fn frame(record: &Record) -> Vec<u8> {
+    let payload = bincode::encode_to_vec(record, config::standard()).unwrap();
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn checksum(payload: &[u8]) -> [u8; 8] {
+    Sha256::digest(payload)[..8].try_into().unwrap()
+}
+
+// Records up to the end or up to the first damaged one, which a crash in the middle
+// of a write leaves behind, and the number of bytes they take
+fn read_journal(data: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = vec![];
+    let mut read = 0;
+    while read < data.len() {
+        let data = &data[read..];
+        let record = data.get(..HEADER_LEN).and_then(|header| {
+            let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            let payload = data.get(HEADER_LEN..HEADER_LEN + len)?;
+            if checksum(payload) != header[4..] {
+                return None;
+            }
+            let (record, _) = bincode::decode_from_slice(payload, config::standard()).ok()?;
+            Some((record, HEADER_LEN + len))
+        });
+        match record {
+            Some((record, len)) => {
+                records.push(record);
+                read += len;
+            }
+            None => break,
+        }
+    }
+    (records, read)
+}
+
+#[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_cache() {
-        let mut cache = super::Cache::new();
-        cache.set("key".to_string(), "value".to_string());
-        assert_eq!(cache.get("key").unwrap(), "value");
-        assert_eq!(cache.get("key").unwrap(), "value");
-        assert_eq!(cache.get("key").unwrap(), "value");
-        assert_eq!(cache.get("key"), None);
+        let file = std::env::temp_dir().join(format!("rustsn_cache_{}.bin", std::process::id()));
+        let file = file.to_str().unwrap();
+        let cache = Cache::open(file);
+        let key = Key::digest(&["cargo build", "fn main() {}"]);
+        cache.set(Namespace::Build, key.clone(), &(0, "ok".to_string()));
+        cache.set(Namespace::Llm, key.clone(), "answer");
+        assert_eq!(
+            cache.get::<(i32, String)>(Namespace::Build, &key),
+            Some((0, "ok".to_string()))
+        );
+        assert_eq!(
+            cache.get::<String>(Namespace::Llm, &key),
+            Some("answer".to_string())
+        );
+        assert_eq!(cache.get::<String>(Namespace::Embedding, &key), None);
+        assert_eq!(cache.get::<Vec<f32>>(Namespace::Llm, &key), None);
+
+        let restored = Cache::open(file);
+        assert_eq!(
+            restored.get::<String>(Namespace::Llm, &key),
+            Some("answer".to_string())
+        );
+
+        // Meta values survive a purge of everything and a restart
+        restored.set_meta(key.clone(), &1024);
+        restored.purge(None, None);
+        let restored = Cache::open(file);
+        assert_eq!(restored.get_meta::<usize>(&key), Some(1024));
+        assert!(restored.list(None).is_empty());
+        let _ = fs::remove_file(file);
+
+        assert_ne!(Key::digest(&["ab", "c"]), Key::digest(&["a", "bc"]));
+        assert_eq!(Key::digest(&[]).as_str().len(), 64);
+    }
+
+    #[test]
+    fn test_journal() {
+        let file = std::env::temp_dir().join(format!("rustsn_journal_{}.bin", std::process::id()));
+        let file = file.to_str().unwrap();
+        let _ = fs::remove_file(file);
+        let first = Key::digest(&["first"]);
+        let second = Key::digest(&["second"]);
+        let cache = Cache::open(file);
+        cache.set(Namespace::Llm, first.clone(), "1");
+        cache.set(Namespace::Llm, second.clone(), "2");
+        drop(cache);
+
+        // A crash in the middle of the last write
+        let len = fs::metadata(file).unwrap().len();
+        let data = fs::read(file).unwrap();
+        fs::write(file, &data[..len as usize - 3]).unwrap();
+        let cache = Cache::open(file);
+        assert_eq!(
+            cache.get::<String>(Namespace::Llm, &first),
+            Some("1".to_string())
+        );
+        assert_eq!(cache.get::<String>(Namespace::Llm, &second), None);
+        cache.set(Namespace::Llm, second.clone(), "2");
+        let cache = Cache::open(file);
+        assert_eq!(
+            cache.get::<String>(Namespace::Llm, &second),
+            Some("2".to_string())
+        );
+
+        // Overwritten records are compacted away
+        for i in 0..MIN_COMPACT_RECORDS {
+            cache.set(Namespace::Llm, first.clone(), &i);
+        }
+        assert!(fs::metadata(file).unwrap().len() < 10_000);
+        let cache = Cache::open(file);
+        assert_eq!(
+            cache.get::<usize>(Namespace::Llm, &first),
+            Some(MIN_COMPACT_RECORDS - 1)
+        );
+        let _ = fs::remove_file(file);
+    }
+
+    #[test]
+    fn test_outdated_format() {
+        let file = std::env::temp_dir().join(format!("rustsn_outdated_{}.bin", std::process::id()));
+        let file = file.to_str().unwrap();
+        let old = format!("{}.old", file);
+        fs::write(file, b"baseline cache").unwrap();
+        let cache = Cache::open(file);
+        assert_eq!(fs::read(&old).unwrap(), b"baseline cache");
+        cache.set(Namespace::Llm, Key::digest(&["first"]), "1");
+        assert!(fs::read(file).unwrap().starts_with(MAGIC));
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(&old);
+    }
+
+    #[test]
+    fn test_inspect() {
+        let file = std::env::temp_dir().join(format!("rustsn_inspect_{}.bin", std::process::id()));
+        let file = file.to_str().unwrap();
+        let _ = fs::remove_file(file);
+        let cache = Cache::open(file);
+        let answer = Key::digest(&["answer"]);
+        cache.set(Namespace::Llm, answer.clone(), "fn solution() {}");
+        cache.set(
+            Namespace::Embedding,
+            Key::digest(&["vector"]),
+            &vec![0.5; 4],
+        );
+
+        let stats = cache.stats();
+        assert_eq!(stats[0].namespace, Namespace::Llm);
+        assert_eq!(stats[0].entries, 1);
+        assert_eq!(stats[1].entries, 0);
+        assert_eq!(cache.list(None).len(), 2);
+        let list = cache.list(Some(Namespace::Llm));
+        assert_eq!(list[0].preview, "\"fn solution() {}\"");
+        let (entry, value) = cache.show(&answer.as_str()[..8]).unwrap();
+        assert_eq!(entry.key, answer.as_str());
+        assert_eq!(value, "\"fn solution() {}\"");
+        assert!(cache.show("").is_err());
+        assert!(cache.show("xyz").is_err());
+
+        assert_eq!(
+            verify(file).unwrap(),
+            Verification {
+                records: 2,
+                entries: 2,
+                ..Verification::default()
+            }
+        );
+        let data = fs::read(file).unwrap();
+        fs::write(file, &data[..data.len() - 3]).unwrap();
+        let verification = verify(file).unwrap();
+        assert_eq!(verification.entries, 1);
+        assert!(verification.damaged_bytes > 0);
+        let _ = fs::remove_file(file);
+    }
+
+    #[test]
+    fn test_limits() {
+        let cache = Cache::in_memory().with_limits(Limits {
+            max_entries: HashMap::from([(Namespace::Llm, 10)]),
+            ttl: HashMap::from([(Namespace::Build, Duration::ZERO)]),
+            ..Limits::default()
+        });
+        let key = |i: usize| Key::digest(&[&i.to_string()]);
+        for i in 0..10 {
+            cache.set(Namespace::Llm, key(i), &i);
+        }
+        // Entry 0 is the most recently used one, 9 and 8 are the least recently used ones
+        {
+            let mut store = cache.store.lock().unwrap();
+            let entries = store.entries.get_mut(&Namespace::Llm).unwrap();
+            for i in 0..10 {
+                entries.get_mut(key(i).as_str()).unwrap().used = 1000 - i as u64;
+            }
+            entries.get_mut(key(0).as_str()).unwrap().used = 2000;
+        }
+        cache.set(Namespace::Llm, key(10), &10);
+        assert_eq!(cache.get::<usize>(Namespace::Llm, &key(0)), Some(0));
+        assert_eq!(cache.get::<usize>(Namespace::Llm, &key(9)), None);
+        assert_eq!(cache.get::<usize>(Namespace::Llm, &key(8)), None);
+        assert_eq!(cache.get::<usize>(Namespace::Llm, &key(7)), Some(7));
+        assert_eq!(cache.get::<usize>(Namespace::Llm, &key(10)), Some(10));
+
+        cache.set(Namespace::Build, key(0), &(0, String::new()));
+        assert_eq!(cache.get::<(i32, String)>(Namespace::Build, &key(0)), None);
+
+        let cache = Cache::in_memory().with_limits(Limits {
+            max_size: Some(1000),
+            ..Limits::default()
+        });
+        for i in 0..20 {
+            cache.set(Namespace::Embedding, key(i), &vec![0.5; 20]);
+        }
+        assert!(cache.store.lock().unwrap().size <= 1000);
+        assert!(cache
+            .get::<Vec<f32>>(Namespace::Embedding, &key(19))
+            .is_some());
+        assert_eq!(cache.purge(None, Some(Duration::from_secs(3600))), 0);
+        assert!(cache.purge(Some(Namespace::Embedding), None) > 0);
+        assert_eq!(cache.store.lock().unwrap().size, 0);
+
+        // Tiny limits keep the entry which was just set
+        let cache = Cache::in_memory().with_limits(Limits {
+            max_entries: HashMap::from([(Namespace::Llm, 1)]),
+            ..Limits::default()
+        });
+        for i in 0..3 {
+            cache.set(Namespace::Llm, key(i), &i);
+            assert_eq!(cache.get::<usize>(Namespace::Llm, &key(i)), Some(i));
+        }
+        assert_eq!(cache.list(Some(Namespace::Llm)).len(), 1);
+        let cache = Cache::in_memory().with_limits(Limits {
+            max_size: Some(5),
+            ..Limits::default()
+        });
+        cache.set(Namespace::Llm, key(0), "longer than the limit");
+        assert!(cache.get::<String>(Namespace::Llm, &key(0)).is_some());
+        cache.set(Namespace::Llm, key(1), "another one");
+        assert!(cache.get::<String>(Namespace::Llm, &key(0)).is_none());
+        assert!(cache.get::<String>(Namespace::Llm, &key(1)).is_some());
     }
 }
@@ -0,0 +1,231 @@
+use crate::cache::{Limits, Namespace};
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Read when no `--config` is given and the file exists in the working folder.
+pub const DEFAULT_CONFIG: &str = "rustsn.json";
+
+/// Settings of `rustsn.json`. Command line arguments override them.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// "ollama", "openai" or "anthropic".
+    pub provider: Option<String>,
+    /// Models to escalate to, in order, like "ollama:qwen2.5-coder:7b" or "openai:gpt-4o".
+    pub fallback: Vec<String>,
+    pub ollama: OllamaConfig,
+    pub cache: CacheConfig,
+}
+
+/// Limits of `cache.bin`, the least recently used entries are evicted first.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Megabytes of all entries together.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size_mb: Option<u64>,
+    pub llm: NamespaceConfig,
+    pub build: NamespaceConfig,
+    pub embedding: NamespaceConfig,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NamespaceConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_entries: Option<usize>,
+    /// Lifetime of an entry like "30d", "12h", "15m" or seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OllamaApi {
+    Generate,
+    Chat,
+}
+
+/// Ollama endpoint and model options, unset ones keep the defaults of the Ollama server.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OllamaConfig {
+    /// Server address like "http://127.0.0.1:11434", the scheme may be omitted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// None sends a single prompt to /api/generate and conversations to /api/chat.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api: Option<OllamaApi>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// Duration like "10m" or seconds, -1 keeps the model loaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<Value>,
+    /// "json" or a JSON schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
+}
+
+impl Config {
+    pub fn load(file_name: Option<&str>) -> Result<Config, String> {
+        let file_name = match file_name {
+            Some(file_name) => file_name,
+            None if std::path::Path::new(DEFAULT_CONFIG).exists() => DEFAULT_CONFIG,
+            None => return Ok(Config::default()),
+        };
+        let content = std::fs::read_to_string(file_name)
+            .map_err(|err| format!("Can't read config \"{}\": {}", file_name, err))?;
+        serde_json::from_str(&content)
+            .map_err(|err| format!("Wrong config \"{}\": {}", file_name, err))
+    }
+}
+
+impl CacheConfig {
+    pub fn limits(&self) -> Result<Limits, String> {
+        let mut limits = Limits {
+            max_size: self.max_size_mb.map(|mb| mb * 1024 * 1024),
+            ..Limits::default()
+        };
+        for (namespace, config) in [
+            (Namespace::Llm, &self.llm),
+            (Namespace::Build, &self.build),
+            (Namespace::Embedding, &self.embedding),
+        ] {
+            if let Some(max_entries) = config.max_entries {
+                limits.max_entries.insert(namespace, max_entries);
+            }
+            if let Some(ttl) = &config.ttl {
+                let ttl = parse_duration(ttl)
+                    .map_err(|err| format!("Wrong ttl of {} cache: {}", namespace, err))?;
+                limits.ttl.insert(namespace, ttl);
+            }
+        }
+        Ok(limits)
+    }
+}
+
+/// Parses "30d", "12h", "15m", "90s" or plain seconds.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let wrong = || format!("\"{}\" is not a duration like 30d, 12h or 15m", text);
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(wrong()),
+    };
+    number
+        .parse::<u64>()
+        .map(|number| Duration::from_secs(number * seconds))
+        .map_err(|_| wrong())
+}
+
+/// Splits "ollama:qwen2.5-coder:7b" into provider and model.
+pub fn split_model(spec: &str) -> Result<(&str, &str), String> {
+    match spec.split_once(':') {
+        Some((provider, model))
+            if ["ollama", "openai", "anthropic"].contains(&provider) && !model.is_empty() =>
+        {
+            Ok((provider, model))
+        }
+        _ => Err(format!(
+            "Wrong fallback model \"{}\", use PROVIDER:MODEL with ollama, openai or anthropic provider",
+            spec
+        )),
+    }
+}
+
+impl OllamaConfig {
+    /// Applies OLLAMA_HOST and then the command line arguments.
+    pub fn override_with(&mut self, matches: &ArgMatches) {
+        if let Ok(host) = std::env::var("OLLAMA_HOST") {
+            if !host.is_empty() {
+                self.url = Some(host);
+            }
+        }
+        if let Some(url) = matches.get_one::<String>("ollama-url") {
+            self.url = Some(url.to_string());
+        }
+        if let Some(api) = matches.get_one::<String>("ollama-api") {
+            self.api = Some(match api.as_str() {
+                "chat" => OllamaApi::Chat,
+                _ => OllamaApi::Generate,
+            });
+        }
+        if let Some(num_ctx) = matches.get_one::<u32>("num-ctx") {
+            self.num_ctx = Some(*num_ctx);
+        }
+        if let Some(temperature) = matches.get_one::<f32>("temperature") {
+            self.temperature = Some(*temperature);
+        }
+        if let Some(top_p) = matches.get_one::<f32>("top-p") {
+            self.top_p = Some(*top_p);
+        }
+        if let Some(seed) = matches.get_one::<i64>("seed") {
+            self.seed = Some(*seed);
+        }
+        if let Some(keep_alive) = matches.get_one::<String>("keep-alive") {
+            self.keep_alive = Some(json_or_string(keep_alive));
+        }
+        if let Some(format) = matches.get_one::<String>("format") {
+            self.format = Some(json_or_string(format));
+        }
+    }
+}
+
+// `-1` and `{"type": "object"}` are JSON, `10m` and `json` are plain strings
+fn json_or_string(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config: Config = serde_json::from_str(
+            r#"{"ollama": {"api": "chat", "num_ctx": 16384, "keep_alive": "10m", "format": "json"}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.ollama.api, Some(OllamaApi::Chat));
+        assert_eq!(config.ollama.num_ctx, Some(16384));
+        assert_eq!(config.ollama.temperature, None);
+        assert_eq!(config.ollama.keep_alive, Some(Value::from("10m")));
+
+        assert_eq!(json_or_string("-1"), Value::from(-1));
+        assert_eq!(
+            split_model("ollama:qwen2.5-coder:7b"),
+            Ok(("ollama", "qwen2.5-coder:7b"))
+        );
+        assert!(split_model("qwen2.5-coder").is_err());
+        assert_eq!(json_or_string("json"), Value::from("json"));
+
+        let config: Config = serde_json::from_str(
+            r#"{"cache": {"max_size_mb": 100, "build": {"ttl": "7d"}, "llm": {"max_entries": 500}}}"#,
+        )
+        .unwrap();
+        let limits = config.cache.limits().unwrap();
+        assert_eq!(limits.max_size, Some(100 * 1024 * 1024));
+        assert_eq!(
+            limits.ttl.get(&Namespace::Build),
+            Some(&Duration::from_secs(7 * 24 * 3600))
+        );
+        assert_eq!(limits.max_entries.get(&Namespace::Llm), Some(&500));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert!(parse_duration("7 weeks").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use crate::llm_api::ChatMessage;
+use crate::tokens::Estimator;
+
+/// Dialogue of the repair loop: the task, every answer of the model and the
+/// build and test feedback to it.
+#[derive(Debug)]
+pub struct Conversation {
+    system: Option<ChatMessage>,
+    task: ChatMessage,
+    turns: Vec<ChatMessage>,
+    max_tokens: usize,
+    estimator: Estimator,
+}
+
+impl Conversation {
+    pub fn new(
+        system: Option<String>,
+        task: &str,
+        max_tokens: usize,
+        estimator: Estimator,
+    ) -> Conversation {
+        Conversation {
+            system: system.map(|system| ChatMessage::system(&system)),
+            task: ChatMessage::user(task),
+            turns: Vec::new(),
+            max_tokens,
+            estimator,
+        }
+    }
+
+    /// Limit and token counter of the model which gets the next messages.
+    pub fn set_model(&mut self, max_tokens: usize, estimator: Estimator) {
+        self.max_tokens = max_tokens;
+        self.estimator = estimator;
+    }
+
+    pub fn push_assistant(&mut self, content: &str) {
+        self.turns.push(ChatMessage::assistant(content));
+    }
+
+    pub fn push_user(&mut self, content: &str) {
+        self.turns.push(ChatMessage::user(content));
+    }
+
+    /// Messages to send. The oldest answer and feedback pairs are left out when the
+    /// history does not fit, the system prompt, the task and the last pair are always kept.
+    /// Left out pairs stay for a model with a larger window.
+    pub fn messages(&self) -> Vec<ChatMessage> {
+        let mut start = 0;
+        while self.turns.len() - start > 2 && self.tokens(start) > self.max_tokens {
+            start += 2;
+        }
+        self.system
+            .iter()
+            .chain([&self.task])
+            .chain(&self.turns[start..])
+            .cloned()
+            .collect()
+    }
+
+    // Tokens of the messages with the turns from `start` on
+    fn tokens(&self, start: usize) -> usize {
+        self.system
+            .iter()
+            .chain([&self.task])
+            .chain(&self.turns[start..])
+            .map(|m| self.estimator.tokens(&m.content))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversation_trim() {
+        let mut conversation =
+            Conversation::new(Some("system".to_string()), "task", 100, estimator());
+        for i in 0..3 {
+            conversation.push_assistant(&format!("answer {} {}", i, "x".repeat(100)));
+            conversation.push_user(&format!("feedback {} {}", i, "x".repeat(100)));
+        }
+        let messages = conversation.messages();
+        let roles = messages.iter().map(|m| m.role.as_str()).collect::<Vec<_>>();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert!(messages[2].content.starts_with("answer 2"));
+        assert!(messages[3].content.starts_with("feedback 2"));
+
+        let mut conversation = Conversation::new(None, "task", 1000, estimator());
+        conversation.push_assistant("answer");
+        conversation.push_user("feedback");
+        assert_eq!(conversation.messages().len(), 3);
+
+        // Escalation to a model with a smaller window
+        conversation.push_assistant(&"x".repeat(300));
+        conversation.push_user("feedback");
+        assert_eq!(conversation.messages().len(), 5);
+        conversation.set_model(50, estimator());
+        assert_eq!(conversation.messages().len(), 3);
+        // and back to a larger one
+        conversation.set_model(1000, estimator());
+        assert_eq!(conversation.messages().len(), 5);
+    }
+
+    fn estimator() -> Estimator {
+        Estimator::for_model("ollama:qwen2.5-coder:7b")
+    }
+}
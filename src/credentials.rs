@@ -0,0 +1,222 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Shorter keys would be given away by their last 4 characters
+const MIN_MASKED_LEN: usize = 12;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    pub key: String,
+    /// Where the key was found, for the startup printout.
+    pub source: String,
+}
+
+impl ApiKey {
+    /// Key with everything but the last 4 characters hidden, short keys are hidden
+    /// completely.
+    pub fn masked(&self) -> String {
+        let chars = self.key.chars().collect::<Vec<_>>();
+        if chars.len() < MIN_MASKED_LEN {
+            return "********".to_string();
+        }
+        format!(
+            "****{}",
+            chars[chars.len() - 4..].iter().collect::<String>()
+        )
+    }
+}
+
+/// Entry of the credentials file: a key or a command printing it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Credential {
+    api_key: Option<String>,
+    command: Option<String>,
+}
+
+/// `$XDG_CONFIG_HOME/rustsn/credentials.json` or `~/.config/rustsn/credentials.json`:
+/// `{"openai": {"api_key": "sk-..."}, "anthropic": {"command": "pass show anthropic"}}`
+pub fn credentials_file() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?)
+            .join(".config"),
+    };
+    Some(config_dir.join("rustsn").join("credentials.json"))
+}
+
+/// Looks for the API key of `provider` ("openai", "anthropic") in this order: command line
+/// argument, `<PROVIDER>_API_KEY` environment variable, key command, credentials file and,
+/// for OpenAI only, the legacy "token.txt".
+pub fn find_api_key(
+    provider: &str,
+    cli_key: Option<&str>,
+    key_command: Option<&str>,
+) -> Result<Option<ApiKey>, String> {
+    find_api_key_in(
+        provider,
+        cli_key,
+        key_command,
+        |name| std::env::var(name).ok(),
+        credentials_file().as_deref(),
+    )
+}
+
+fn find_api_key_in(
+    provider: &str,
+    cli_key: Option<&str>,
+    key_command: Option<&str>,
+    env: impl Fn(&str) -> Option<String>,
+    credentials_file: Option<&Path>,
+) -> Result<Option<ApiKey>, String> {
+    if let Some(key) = cli_key {
+        return Ok(Some(ApiKey {
+            key: key.to_string(),
+            source: format!("--{}-key argument", provider),
+        }));
+    }
+    let env_name = format!("{}_API_KEY", provider.to_uppercase());
+    if let Some(key) = env(&env_name).filter(|key| !key.trim().is_empty()) {
+        return Ok(Some(ApiKey {
+            key: key.trim().to_string(),
+            source: format!("{} environment variable", env_name),
+        }));
+    }
+    if let Some(command) = key_command {
+        return Ok(Some(ApiKey {
+            key: run_key_command(command)?,
+            source: format!("command \"{}\"", command),
+        }));
+    }
+    if let Some(file) = credentials_file.filter(|file| file.exists()) {
+        if let Some(credential) = read_credentials(file)?.remove(provider) {
+            if let Some(key) = credential.api_key {
+                return Ok(Some(ApiKey {
+                    key,
+                    source: file.display().to_string(),
+                }));
+            }
+            if let Some(command) = credential.command {
+                return Ok(Some(ApiKey {
+                    key: run_key_command(&command)?,
+                    source: format!("command \"{}\" of {}", command, file.display()),
+                }));
+            }
+        }
+    }
+    if provider == "openai" && Path::new("token.txt").exists() {
+        let key = std::fs::read_to_string("token.txt").map_err(|err| err.to_string())?;
+        return Ok(Some(ApiKey {
+            key: key.trim().to_string(),
+            source: "token.txt (deprecated, use OPENAI_API_KEY)".to_string(),
+        }));
+    }
+    Ok(None)
+}
+
+fn read_credentials(file: &Path) -> Result<HashMap<String, Credential>, String> {
+    check_permissions(file)?;
+    let content = std::fs::read_to_string(file)
+        .map_err(|err| format!("Can't read credentials \"{}\": {}", file.display(), err))?;
+    serde_json::from_str(&content)
+        .map_err(|err| format!("Wrong credentials \"{}\": {}", file.display(), err))
+}
+
+// Like ssh with private keys, refuse a file other users can read
+#[cfg(unix)]
+fn check_permissions(file: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(file)
+        .map_err(|err| err.to_string())?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "Credentials \"{}\" are accessible by other users, run: chmod 600 {}",
+            file.display(),
+            file.display()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_file: &Path) -> Result<(), String> {
+    Ok(())
+}
+
+fn run_key_command(command: &str) -> Result<String, String> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").args(["/C", command]).output()
+    } else {
+        Command::new("sh").args(["-c", command]).output()
+    }
+    .map_err(|err| format!("Can't run key command \"{}\": {}", command, err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Key command \"{}\" failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if key.is_empty() {
+        return Err(format!("Key command \"{}\" printed nothing", command));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_api_key() {
+        let env = |name: &str| match name {
+            "ANTHROPIC_API_KEY" => Some("sk-ant-from-env".to_string()),
+            _ => None,
+        };
+        let key = find_api_key_in("openai", Some("sk-cli"), None, env, None).unwrap();
+        let key = key.unwrap();
+        assert_eq!(key.source, "--openai-key argument");
+        assert_eq!(key.masked(), "********");
+
+        let key = find_api_key_in("anthropic", None, None, env, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(key.key, "sk-ant-from-env");
+        assert_eq!(key.masked(), "****-env");
+
+        let key = find_api_key_in("openai", None, Some("echo sk-command"), env, None).unwrap();
+        assert_eq!(key.unwrap().key, "sk-command");
+        assert!(find_api_key_in("openai", None, Some("exit 1"), env, None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_credentials_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file =
+            std::env::temp_dir().join(format!("rustsn_credentials_{}.json", std::process::id()));
+        std::fs::write(
+            &file,
+            r#"{"openai": {"api_key": "sk-from-file"}, "anthropic": {"command": "echo sk-ant"}}"#,
+        )
+        .unwrap();
+        let no_env = |_: &str| None;
+
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(find_api_key_in("openai", None, None, no_env, Some(&file)).is_err());
+
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let key = find_api_key_in("openai", None, None, no_env, Some(&file)).unwrap();
+        assert_eq!(key.unwrap().key, "sk-from-file");
+        let key = find_api_key_in("anthropic", None, None, no_env, Some(&file)).unwrap();
+        assert_eq!(key.unwrap().key, "sk-ant");
+
+        std::fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use crate::llm_api::{
+    read_json, send, ChatMessage, ChatRequest, ChatResponse, LlmBackend, LlmError, Usage,
+};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+pub const ANTHROPIC_URL: &str = "https://api.anthropic.com";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+// Tool which takes the answer in structured output mode
+const ANSWER_TOOL: &str = "project";
+
+pub struct AnthropicBackend {
+    base_url: String,
+    model: String,
+    api_key: String,
+    client: Client,
+}
+
+impl AnthropicBackend {
+    pub fn new(base_url: &str, model: String, api_key: String) -> AnthropicBackend {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(60 * 5))
+            .build()
+            .unwrap();
+        AnthropicBackend {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model,
+            api_key,
+            client,
+        }
+    }
+}
+
+impl LlmBackend for AnthropicBackend {
+    fn name(&self) -> String {
+        format!("anthropic:{}", self.model)
+    }
+
+    fn emb_name(&self) -> String {
+        "anthropic".to_string()
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        Some(200_000)
+    }
+
+    fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        // The Messages API takes system prompts as a separate field, not as a message role
+        let system = request
+            .messages
+            .iter()
+            .filter(|m| m.role == "system")
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let request = AnthropicRequest {
+            model: self.model.to_string(),
+            max_tokens: request.max_tokens,
+            system: if system.is_empty() {
+                None
+            } else {
+                Some(system)
+            },
+            messages: request
+                .messages
+                .iter()
+                .filter(|m| m.role != "system")
+                .cloned()
+                .collect(),
+            stop_sequences: request.stop.clone(),
+            // The Messages API has no seed
+            temperature: request.sampling.temperature,
+            // Structured output is a forced call of a tool with the schema as its input
+            tools: request
+                .schema
+                .iter()
+                .map(|schema| AnthropicTool {
+                    name: ANSWER_TOOL.to_string(),
+                    description: "Answer with the project files and commands".to_string(),
+                    input_schema: schema.clone(),
+                })
+                .collect(),
+            tool_choice: request
+                .schema
+                .as_ref()
+                .map(|_| json!({"type": "tool", "name": ANSWER_TOOL})),
+        };
+
+        let response = send(
+            self.client
+                .post(format!("{}/v1/messages", self.base_url))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", ANTHROPIC_VERSION)
+                .json(&request),
+        )?;
+        let response = read_json::<AnthropicResponse>(response)?;
+
+        let content = match response.content.iter().find_map(|block| {
+            block
+                .input
+                .as_ref()
+                .filter(|_| block.block_type == "tool_use")
+        }) {
+            Some(input) => input.to_string(),
+            None => response
+                .content
+                .into_iter()
+                .filter(|block| block.block_type == "text")
+                .map(|block| block.text)
+                .collect::<Vec<_>>()
+                .join(""),
+        };
+        Ok(ChatResponse {
+            content,
+            usage: Some(Usage {
+                prompt_tokens: response.usage.input_tokens,
+                completion_tokens: response.usage.output_tokens,
+            }),
+        })
+    }
+
+    fn emb(&self, _content: &str) -> Result<Vec<f32>, LlmError> {
+        Err(LlmError::Unsupported(
+            "Anthropic API does not provide embeddings, use Ollama or OpenAI for 'ask' command"
+                .to_string(),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AnthropicRequest {
+    model: String,
+    max_tokens: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<AnthropicTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AnthropicTool {
+    name: String,
+    description: String,
+    input_schema: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AnthropicResponse {
+    content: Vec<AnthropicContentBlock>,
+    stop_reason: Option<String>,
+    usage: AnthropicUsage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AnthropicContentBlock {
+    #[serde(rename = "type")]
+    block_type: String,
+    #[serde(default)]
+    text: String,
+    /// Arguments of a "tool_use" block.
+    input: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AnthropicUsage {
+    input_tokens: i64,
+    output_tokens: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_api::Sampling;
+    use crate::mock_server::{MockResponse, MockServer};
+
+    #[test]
+    fn test_anthropic_chat() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"id":"msg_1","type":"message","role":"assistant","model":"claude-test",
+                "content":[{"type":"text","text":"Hello"},{"type":"text","text":" world"}],
+                "stop_reason":"end_turn","stop_sequence":null,
+                "usage":{"input_tokens":12,"output_tokens":3}}"#,
+        )]);
+        let backend =
+            AnthropicBackend::new(&server.url, "claude-test".to_string(), "key".to_string());
+        let response = backend
+            .chat(&ChatRequest {
+                messages: vec![
+                    ChatMessage {
+                        role: "system".to_string(),
+                        content: "Be brief".to_string(),
+                    },
+                    ChatMessage::user("Say hello"),
+                ],
+                max_tokens: 100,
+                stop: vec!["**Notes".to_string()],
+                schema: None,
+                phase: String::new(),
+                sampling: Sampling::default(),
+            })
+            .unwrap();
+
+        assert_eq!(response.content, "Hello world");
+        assert_eq!(
+            response.usage,
+            Some(Usage {
+                prompt_tokens: 12,
+                completion_tokens: 3
+            })
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/v1/messages");
+        assert_eq!(requests[0].header("x-api-key"), Some("key"));
+        assert_eq!(
+            requests[0].header("anthropic-version"),
+            Some(ANTHROPIC_VERSION)
+        );
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["system"], "Be brief");
+        assert_eq!(body["max_tokens"], 100);
+        assert_eq!(body["stop_sequences"][0], "**Notes");
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(body["messages"][0]["role"], "user");
+        assert!(body.get("tools").is_none());
+    }
+
+    #[test]
+    fn test_anthropic_schema() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"content":[{"type":"tool_use","id":"toolu_1","name":"project",
+                "input":{"solution_code":"fn solution() {}"}}],
+                "stop_reason":"tool_use","usage":{"input_tokens":12,"output_tokens":3}}"#,
+        )]);
+        let backend =
+            AnthropicBackend::new(&server.url, "claude-test".to_string(), "key".to_string());
+        let schema = json!({"type": "object", "properties": {"solution_code": {"type": "string"}}});
+        let response = backend
+            .chat(&ChatRequest {
+                messages: vec![ChatMessage::user("Write solution")],
+                max_tokens: 100,
+                stop: vec![],
+                schema: Some(schema.clone()),
+                phase: String::new(),
+                sampling: Sampling::default(),
+            })
+            .unwrap();
+        assert_eq!(response.content, r#"{"solution_code":"fn solution() {}"}"#);
+
+        let body: Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(body["tools"][0]["input_schema"], schema);
+        assert_eq!(body["tool_choice"]["name"], "project");
+    }
+}
@@ -1,9 +1,16 @@
-use crate::cache::Cache;
+use crate::cache::{Cache, Key, Namespace};
 use crate::llm_prompt::Prompt;
-use crate::{OLLAMA_API, OLLAMA_EMB, VERBOSE};
-use reqwest::blocking::Client;
+use crate::llm_retry::RetryPolicy;
+use crate::tokens::Estimator;
+use crate::usage::{Budget, UsageRecord, UsageTracker};
+use crate::VERBOSE;
+use reqwest::blocking::{RequestBuilder, Response};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 // const STOP_WORDS: &[&str] = &[
 //     "**Explanation",
@@ -12,350 +19,831 @@ use std::time::Duration;
 //     "**Additional Notes",
 // ];
 const STOP_WORDS: &[&str] = &[];
-const MAX_TOKENS: i32 = 1000;
+/// Longest answer requested from the model.
+pub const MAX_TOKENS: i32 = 1000;
+
+/// Provider of chat completions and embeddings.
+///
+/// Implementations only talk to their API: caching and logging are done once by `LLMApi`.
+/// Parallel candidates share one backend, hence `Send + Sync`.
+pub trait LlmBackend: Send + Sync {
+    /// Provider and chat model, e.g. "ollama:qwen2.5-coder:7b". Part of the cache key.
+    fn name(&self) -> String;
+    /// Provider and embedding model, e.g. "ollama:bge-large".
+    fn emb_name(&self) -> String;
+    /// Model and the settings that change its answers. Part of the cache key.
+    fn cache_name(&self) -> String {
+        self.name()
+    }
+    /// Tokens of prompt and answer together, None when unknown.
+    fn context_window(&self) -> Option<usize> {
+        None
+    }
+    fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError>;
+    fn emb(&self, content: &str) -> Result<Vec<f32>, LlmError>;
+    /// Embeddings of several texts in one request, in the same order.
+    fn emb_batch(&self, contents: &[String]) -> Result<EmbResponse, LlmError> {
+        Ok(EmbResponse {
+            embeddings: contents
+                .iter()
+                .map(|content| self.emb(content))
+                .collect::<Result<_, _>>()?,
+            usage: None,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum LlmError {
+    /// Connection refused, timeout or a response body cut off in the middle.
+    Transport(String),
+    /// Non-2xx answer other than rate limiting.
+    Http {
+        status: u16,
+        body: String,
+        retry_after: Option<Duration>,
+    },
+    /// Response does not match the expected JSON.
+    Decode(String),
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// The provider can't answer such request, e.g. embeddings of Anthropic
+    /// or a response missing in replay fixtures.
+    Unsupported(String),
+    /// Session limit of tokens, cost or time; the request was not sent.
+    BudgetExceeded(String),
+}
+
+impl Display for LlmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LlmError::Transport(message) => write!(f, "connection to LLM failed: {}", message),
+            LlmError::Http { status, body, .. } => {
+                write!(f, "LLM returned HTTP {}: {}", status, body)
+            }
+            LlmError::Decode(message) => write!(f, "unexpected LLM response: {}", message),
+            LlmError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "LLM rate limit exceeded, retry after {} s",
+                retry_after.as_secs()
+            ),
+            LlmError::RateLimited { retry_after: None } => write!(f, "LLM rate limit exceeded"),
+            LlmError::Unsupported(message) => write!(f, "{}", message),
+            LlmError::BudgetExceeded(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(err: reqwest::Error) -> Self {
+        LlmError::Transport(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for LlmError {
+    fn from(err: std::io::Error) -> Self {
+        LlmError::Transport(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for LlmError {
+    fn from(err: serde_json::Error) -> Self {
+        LlmError::Decode(err.to_string())
+    }
+}
+
+/// Sends an HTTP request to the provider and turns transport failures and
+/// unsuccessful statuses into `LlmError`.
+pub fn send(request: RequestBuilder) -> Result<Response, LlmError> {
+    let response = request.send()?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    // Only the delay-seconds form of Retry-After, HTTP dates fall back to backoff
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    if status.as_u16() == 429 {
+        return Err(LlmError::RateLimited { retry_after });
+    }
+    Err(LlmError::Http {
+        status: status.as_u16(),
+        body: response.text().unwrap_or_default(),
+        retry_after,
+    })
+}
+
+/// Reads the whole body and deserializes it, keeping the body in the error for diagnostics.
+pub fn read_json<T: DeserializeOwned>(response: Response) -> Result<T, LlmError> {
+    let text = response.text()?;
+    serde_json::from_str(&text).map_err(|err| LlmError::Decode(format!("{}: {}", err, text)))
+}
+
 pub struct LLMApi {
-    model_type: ModelType,
+    // Fallback chain, callers choose the chat model by its position, embeddings always
+    // come from the first one
+    backends: Vec<Box<dyn LlmBackend>>,
+    retry: RetryPolicy,
+    usage: UsageTracker,
+    budget: Budget,
+    emb_batch_size: usize,
+    emb_concurrency: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub messages: Vec<ChatMessage>,
+    pub max_tokens: i32,
+    pub stop: Vec<String>,
+    /// JSON schema of the answer for providers with structured output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+    /// Prompt name like "generate" or "rewrite". Not part of the cache key.
+    #[serde(skip)]
+    pub phase: String,
+    #[serde(flatten)]
+    pub sampling: Sampling,
+}
+
+/// Sampling settings of a single request, None keeps the ones of the backend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Sampling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String, // e.g., "user", "assistant", "system"
+    pub content: String,
 }
 
-#[derive(Debug, PartialEq)]
-pub enum ModelType {
-    Ollama { model: String, emb: String },
-    OpenAI { api_key: String },
+#[derive(Debug, Clone, Default)]
+pub struct ChatResponse {
+    pub content: String,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EmbResponse {
+    pub embeddings: Vec<Vec<f32>>,
+    /// Tokens of all texts as `prompt_tokens`, None when the provider does not tell.
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+impl ChatMessage {
+    pub fn user(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: "user".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    pub fn system(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: "system".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    pub fn assistant(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: "assistant".to_string(),
+            content: content.to_string(),
+        }
+    }
 }
 
 impl LLMApi {
-    pub fn new(model_type: ModelType) -> LLMApi {
-        LLMApi { model_type }
+    pub fn new(backend: Box<dyn LlmBackend>) -> LLMApi {
+        LLMApi {
+            backends: vec![backend],
+            retry: RetryPolicy::none(),
+            usage: UsageTracker::default(),
+            budget: Budget::unlimited(),
+            emb_batch_size: 1,
+            emb_concurrency: 1,
+        }
+    }
+
+    // The server cuts the beginning of a prompt which does not fit, silently. Every such
+    // prompt is reported, an answer to a cut prompt is worth a look in every phase.
+    fn warn_context_window(&self, backend: &dyn LlmBackend, phase: &str, prompt_tokens: usize) {
+        let window = match backend.context_window() {
+            Some(window) => window,
+            None => return,
+        };
+        if prompt_tokens + MAX_TOKENS as usize > window {
+            println!(
+                "Warning: {} prompt of about {} tokens and answer of up to {} tokens exceed the context window of {} ({} tokens)",
+                phase,
+                prompt_tokens,
+                MAX_TOKENS,
+                backend.name(),
+                window
+            );
+        }
+    }
+
+    /// Texts per embedding request and requests sent at once by `emb_batch`.
+    pub fn with_emb_batch(mut self, batch_size: usize, concurrency: usize) -> LLMApi {
+        self.emb_batch_size = batch_size.max(1);
+        self.emb_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Adds the next model of the fallback chain, see `escalate`.
+    pub fn with_fallback(mut self, backend: Box<dyn LlmBackend>) -> LLMApi {
+        self.backends.push(backend);
+        self
+    }
+
+    // `model` is the position in the fallback chain, 0 for the main model
+    fn backend(&self, model: usize) -> &dyn LlmBackend {
+        self.backends[model.min(self.backends.len() - 1)].as_ref()
+    }
+
+    /// Position and name of the model after `model` in the fallback chain, None when
+    /// `model` is the last one.
+    pub fn escalate(&self, model: usize) -> Option<(usize, String)> {
+        let next = model + 1;
+        if next >= self.backends.len() {
+            return None;
+        }
+        Some((next, self.backends[next].name()))
+    }
+
+    /// Chat models of the fallback chain, in order.
+    pub fn models(&self) -> Vec<String> {
+        self.backends.iter().map(|backend| backend.name()).collect()
+    }
+
+    /// Embedding model, only the first backend of the chain embeds.
+    pub fn emb_model(&self) -> String {
+        self.backends[0].emb_name()
+    }
+
+    /// Token counter of the model at position `model` of the fallback chain.
+    pub fn estimator(&self, model: usize) -> Estimator {
+        Estimator::for_model(&self.backend(model).name())
+    }
+
+    /// Context window of the model at position `model`, None when unknown.
+    pub fn context_window(&self, model: usize) -> Option<usize> {
+        self.backend(model).context_window()
+    }
+
+    pub fn with_budget(mut self, budget: Budget) -> LLMApi {
+        self.budget = budget;
+        self
+    }
+
+    pub fn with_usage(mut self, usage: UsageTracker) -> LLMApi {
+        self.usage = usage;
+        self
+    }
+
+    pub fn usage(&self) -> &UsageTracker {
+        &self.usage
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> LLMApi {
+        self.retry = retry;
+        self
     }
 
     pub fn request(
         &self,
         prompt_template: &str,
         params: &Vec<String>,
-        cache: &mut Cache,
+        cache: &Cache,
         prompt: &Prompt,
-    ) -> String {
-        let prompt = if params.len() > 0 {
-            prompt.create(prompt_template, params)
+    ) -> Result<String, LlmError> {
+        // Raw prompts without template only come from "ask" command
+        let (phase, prompt) = if !params.is_empty() {
+            (prompt_template, prompt.create(prompt_template, params))
         } else {
-            prompt_template.to_string()
+            ("ask", prompt_template.to_string())
         };
-        match &self.model_type {
-            ModelType::Ollama { model, .. } => {
-                let stop = STOP_WORDS;
-                let request = OllamaRequest {
-                    // model: "qwen2.5-coder:7b".to_string(), // smart model but slow
-                    // model: "qwen2.5-coder:1.5b".to_string(), // smart model but slow
-                    model: model.to_string(),
-                    // model: "gemma2:2b".to_string(), // fast but very stupid model - excellent for fast testing
-                    //  model: "gemma2".to_string(), // medium model
-                    prompt: prompt.to_string(),
-                    stream: false,
-                    options: OllamaOptions {
-                        num_predict: MAX_TOKENS,
-                        stop: stop.iter().map(|s| s.to_string()).collect(),
-                    },
-                };
+        self.chat(phase, vec![ChatMessage::user(&prompt)], None, cache)
+    }
 
-                let request_str = serde_json::to_string(&request).unwrap();
-                if *VERBOSE.lock().unwrap() {
-                    println!("Request: {}", request.prompt);
-                }
+    /// Sends a whole conversation to the main model, `phase` names it in the usage
+    /// statistics. With `schema` the answer is requested as JSON matching it.
+    pub fn chat(
+        &self,
+        phase: &str,
+        messages: Vec<ChatMessage>,
+        schema: Option<serde_json::Value>,
+        cache: &Cache,
+    ) -> Result<String, LlmError> {
+        self.chat_with(0, phase, messages, schema, Sampling::default(), cache)
+    }
 
-                let response_opt = cache.get(&request_str);
-                let response = match response_opt {
-                    None => {
-                        let client = Client::builder()
-                            .timeout(Duration::from_secs(60 * 10))
-                            .build()
-                            .unwrap();
-                        println!("Request to LLM in progress");
-
-                        let response = client
-                            .post(OLLAMA_API)
-                            .json(&request)
-                            .send()
-                            .unwrap();
-                        if !response.status().is_success(){
-                            let response_text = response.text().unwrap();
-                            println!("Response: {:?}", response_text);
-                            panic!("Failed to get response from LLM");
-                        }
-                        let response_text = response.text().unwrap();
-                        let response = serde_json::from_str::<OllamaResponse>(&response_text).unwrap();
-                        cache.set(request_str.clone(), response.response.clone());
-                        response.response
-                    }
-                    Some(result) => {
-                        println!("LLM Request already cached");
-                        result.to_string()
-                    }
-                };
+    /// Like `chat`, to the model at position `model` of the fallback chain and with own
+    /// temperature or seed. They are part of the cache key.
+    pub fn chat_with(
+        &self,
+        model: usize,
+        phase: &str,
+        messages: Vec<ChatMessage>,
+        schema: Option<serde_json::Value>,
+        sampling: Sampling,
+        cache: &Cache,
+    ) -> Result<String, LlmError> {
+        let backend = self.backend(model);
+        let request = ChatRequest {
+            messages,
+            max_tokens: MAX_TOKENS,
+            stop: STOP_WORDS.iter().map(|s| s.to_string()).collect(),
+            schema,
+            phase: phase.to_string(),
+            sampling,
+        };
 
-                if *VERBOSE.lock().unwrap() {
-                    println!("Response: {}", response);
-                }
-                response
+        let key = Key::digest(&[
+            &backend.cache_name(),
+            &serde_json::to_string(&request).unwrap(),
+        ]);
+        if *VERBOSE.lock().unwrap() {
+            for message in &request.messages {
+                println!("Request ({}): {}", message.role, message.content);
             }
-            ModelType::OpenAI { api_key } => {
-                let messages = vec![ChatMessage {
-                    role: "user".to_string(),
-                    content: prompt.to_string(),
-                }];
-
-                let request = OpenAIChatRequest {
-                    model: "gpt-4o-2024-08-06".to_string(),
-                    messages,
-                    max_tokens: MAX_TOKENS,
-                    temperature: 0.7,
-                    stop: Some(STOP_WORDS.iter().map(|s| s.to_string()).collect()),
-                };
+        }
 
-                let request_str = serde_json::to_string(&request).unwrap();
+        let response = match cache.get::<String>(Namespace::Llm, &key) {
+            None => {
+                let estimator = Estimator::for_model(&backend.name());
+                let prompt_tokens = request
+                    .messages
+                    .iter()
+                    .map(|m| estimator.tokens(&m.content))
+                    .sum::<usize>();
+                self.usage
+                    .check_budget(
+                        &self.budget,
+                        &backend.name(),
+                        prompt_tokens as i64,
+                        request.max_tokens as i64,
+                    )
+                    .map_err(LlmError::BudgetExceeded)?;
+                self.warn_context_window(backend, &request.phase, prompt_tokens);
+                println!("Request to LLM in progress");
+                let start = Instant::now();
+                let response = self.retry.run(|| backend.chat(&request))?;
+                // Replayed fixtures and servers ignoring `include_usage` tell no usage,
+                // the token budget must count such calls as well
+                let usage = response.usage.unwrap_or(Usage {
+                    prompt_tokens: prompt_tokens as i64,
+                    completion_tokens: estimator.tokens(&response.content) as i64,
+                });
                 if *VERBOSE.lock().unwrap() {
-                    println!("Request: {}", prompt);
+                    println!(
+                        "Tokens: prompt {}, completion {}",
+                        usage.prompt_tokens, usage.completion_tokens
+                    );
                 }
+                self.usage.record(UsageRecord {
+                    phase: phase.to_string(),
+                    model: backend.name(),
+                    prompt_tokens: usage.prompt_tokens,
+                    completion_tokens: usage.completion_tokens,
+                    latency: start.elapsed(),
+                    cached: false,
+                });
+                cache.set(Namespace::Llm, key, &response.content);
+                response.content
+            }
+            Some(result) => {
+                println!("LLM Request already cached");
+                self.record_cached(phase, backend.name());
+                result
+            }
+        };
 
-                let response_opt = cache.get(&request_str);
-                let response = match response_opt {
-                    None => {
-                        println!("Request to LLM in progress");
-                        let client = Client::builder()
-                            .timeout(Duration::from_secs(60 * 5))
-                            .build()
-                            .unwrap();
-
-                        let response = client
-                            .post("https://api.openai.com/v1/chat/completions")
-                            .bearer_auth(api_key)
-                            .json(&request)
-                            .send()
-                            .unwrap()
-                            .json::<OpenAIChatResponse>()
-                            .unwrap();
-
-                        // Extract the assistant's reply from the first choice
-                        let openai_response = response
-                            .choices
-                            .into_iter()
-                            .next()
-                            .map(|choice| choice.message.content)
-                            .unwrap_or_default();
-
-                        cache.set(request_str.clone(), openai_response.clone());
-                        openai_response
-                    }
-                    Some(result) => {
-                        println!("LLM Request already cached");
-                        result.to_string()
-                    }
-                };
+        if *VERBOSE.lock().unwrap() {
+            println!("Response: {}", response);
+        }
+        Ok(response)
+    }
 
-                if *VERBOSE.lock().unwrap() {
-                    println!("OpenAI Chat Response: {}", response);
-                }
+    pub fn emb(
+        &self,
+        content: &str,
+        cache: &Cache,
+        full_content: &str,
+    ) -> Result<Vec<f32>, LlmError> {
+        let cached = self.cached_emb(cache, self.emb_dimension(cache), content);
+        let response = match cached {
+            None => {
+                println!("Request to Embeddings API in progress");
+                let response = self
+                    .emb_request(self.backends[0].as_ref(), &[full_content.to_string()])?
+                    .swap_remove(0);
+                self.update_emb_dimension(cache, response.len());
+                cache.set(
+                    Namespace::Embedding,
+                    self.emb_key(response.len(), content),
+                    &response,
+                );
                 response
             }
+            Some(result) => {
+                println!("Embedding Request already cached");
+                self.record_cached("embedding", self.backends[0].emb_name());
+                result
+            }
+        };
+
+        if *VERBOSE.lock().unwrap() {
+            println!("Embedding Response: {:?}", response);
         }
+        Ok(response)
     }
-    pub fn emb(&self, content: &str, cache: &mut Cache, full_content: &str) -> Vec<f32> {
-        match &self.model_type {
-            ModelType::Ollama { emb, .. } => {
-                let request = OllamaEmbRequest {
-                    model: emb.to_string(),
-                    prompt: full_content.to_string(),
-                };
 
-                let request_str = serde_json::to_string(&content).unwrap();
-                let response_opt = cache.get(&request_str);
-                let response = match response_opt {
-                    None => {
-                        println!("Request to Ollama Embeddings API in progress");
-                        let client = Client::builder()
-                            .timeout(Duration::from_secs(60 * 10))
-                            .build()
-                            .unwrap();
-                        // let response = client
-                        //     .post(OLLAMA_EMB)
-                        //     .json(&request)
-                        //     .send()
-                        //     .unwrap()
-                        //     .json::<OllamaEmbResponse>()
-                        //     .unwrap();
-                        //
-                        let response_str = client
-                            .post(OLLAMA_EMB)
-                            .json(&request)
-                            .send()
-                            .unwrap()
-                            .text()
-                            .unwrap();
-                        // println!("Response: {}", response_str);
-                        let response: OllamaEmbResponse =
-                            serde_json::from_str(&response_str).unwrap();
-                        cache.set(
-                            request_str.clone(),
-                            serde_json::to_string(&response.embedding).unwrap(),
-                        );
-                        response.embedding
-                    }
-                    Some(result) => {
-                        println!("Embedding Request already cached");
-                        serde_json::from_str(&result).unwrap()
-                    }
-                };
-                response
+    /// Embeddings of many texts: cached ones are taken from `cache`, the rest is sent in
+    /// batches by several threads. `progress` gets the number of ready and all texts.
+    pub fn emb_batch(
+        &self,
+        contents: &[String],
+        cache: &Cache,
+        progress: &(dyn Fn(usize, usize) + Sync),
+    ) -> Result<Vec<Vec<f32>>, LlmError> {
+        let backend = self.backends[0].as_ref();
+        let dimension = self.emb_dimension(cache);
+        let mut results = vec![None; contents.len()];
+        let mut missing = vec![];
+        for (index, content) in contents.iter().enumerate() {
+            match self.cached_emb(cache, dimension, content) {
+                Some(result) => {
+                    self.record_cached("embedding", backend.emb_name());
+                    results[index] = Some(result);
+                }
+                None => missing.push(index),
             }
-            ModelType::OpenAI { api_key } => {
-                let request = OpenAIEmbRequest {
-                    model: "text-embedding-ada-002".to_string(),
-                    input: full_content.to_string(),
-                };
-
-                let request_str = serde_json::to_string(&request).unwrap();
-
-                let response_opt = cache.get(&content);
-
-                let response = match response_opt {
-                    None => {
-                        let client = Client::builder()
-                            .timeout(Duration::from_secs(60 * 5))
-                            .build()
-                            .unwrap();
-
-                        println!("Request to OpenAI Embeddings API in progress");
-
-                        let api_response = match client
-                            .post("https://api.openai.com/v1/embeddings")
-                            .bearer_auth(api_key)
-                            .json(&request)
-                            .send()
-                        {
-                            Ok(resp) => resp,
-                            Err(e) => {
-                                eprintln!("Network error: {}", e);
-                                return vec![];
-                            }
-                        };
-
-                        let api_response = match api_response.json::<OpenAIEmbResponse>() {
-                            Ok(json) => json,
-                            Err(e) => {
-                                eprintln!("Failed to parse JSON response: {}", e);
-                                return vec![];
-                            }
-                        };
-
-                        cache.set(
-                            request_str.clone(),
-                            serde_json::to_string(&api_response.data[0].embedding).unwrap(),
-                        );
-                        api_response.data[0].embedding.clone()
-                    }
-                    Some(result) => {
-                        println!("Embedding Request already cached");
-                        serde_json::from_str(&result).unwrap()
+        }
+        let ready = AtomicUsize::new(contents.len() - missing.len());
+        progress(ready.load(Ordering::SeqCst), contents.len());
+
+        let batches = missing.chunks(self.emb_batch_size).collect::<Vec<_>>();
+        let next_batch = AtomicUsize::new(0);
+        let results = Mutex::new(results);
+        // Workers take the batches one by one until all are done or one of them fails
+        let worker = || -> Result<(), LlmError> {
+            while let Some(batch) = batches.get(next_batch.fetch_add(1, Ordering::SeqCst)) {
+                let texts = batch
+                    .iter()
+                    .map(|index| contents[*index].clone())
+                    .collect::<Vec<_>>();
+                let embeddings = match self.emb_request(backend, &texts) {
+                    Ok(embeddings) => embeddings,
+                    Err(err) => {
+                        next_batch.store(batches.len(), Ordering::SeqCst);
+                        return Err(err);
                     }
                 };
-
-                if *VERBOSE.lock().unwrap() {
-                    println!("OpenAI Embedding Response: {:?}", response);
+                let mut results = results.lock().unwrap();
+                for (index, embedding) in batch.iter().zip(embeddings) {
+                    cache.set(
+                        Namespace::Embedding,
+                        self.emb_key(embedding.len(), &contents[*index]),
+                        &embedding,
+                    );
+                    results[*index] = Some(embedding);
                 }
-                response.to_vec()
+                let ready = ready.fetch_add(batch.len(), Ordering::SeqCst) + batch.len();
+                progress(ready, contents.len());
+            }
+            Ok(())
+        };
+        let workers = self.emb_concurrency.min(batches.len());
+        std::thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| scope.spawn(worker))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        let results = results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|embedding| embedding.unwrap())
+            .collect::<Vec<_>>();
+
+        // Vectors of different sizes can't be compared, so the cached ones of another
+        // dimension are requested again
+        let fresh = missing
+            .iter()
+            .map(|index| results[*index].len())
+            .collect::<Vec<_>>();
+        if let Some(new_dimension) = fresh.first().copied() {
+            if fresh.iter().any(|dimension| *dimension != new_dimension) {
+                return Err(LlmError::Decode(
+                    "embeddings of different dimensions in one session".to_string(),
+                ));
+            }
+            self.update_emb_dimension(cache, new_dimension);
+            if results.iter().any(|result| result.len() != new_dimension) {
+                return self.emb_batch(contents, cache, progress);
             }
         }
+        Ok(results)
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OllamaRequest {
-    model: String,
-    prompt: String,
-    stream: bool,
-    options: OllamaOptions,
-}
+    // Provider, model and size of vectors, so another embedding model never gets
+    // vectors of the previous one
+    fn emb_key(&self, dimension: usize, content: &str) -> Key {
+        Key::digest(&[
+            &self.backends[0].emb_name(),
+            &dimension.to_string(),
+            content,
+        ])
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OllamaOptions {
-    num_predict: i32,
-    stop: Vec<String>,
-}
+    // Only vectors of the dimension of the last answer of the model are current, before
+    // the first answer nothing is
+    fn cached_emb(
+        &self,
+        cache: &Cache,
+        dimension: Option<usize>,
+        content: &str,
+    ) -> Option<Vec<f32>> {
+        let key = self.emb_key(dimension?, content);
+        cache.get(Namespace::Embedding, &key)
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OllamaResponse {
-    model: String,
-    created_at: String,
-    response: String,
-    done: bool,
-    done_reason: String,
-    context: Vec<i64>,
-    total_duration: i64,
-    load_duration: i64,
-    prompt_eval_count: i32,
-    prompt_eval_duration: i64,
-    eval_count: i32,
-    eval_duration: i64,
-}
+    fn emb_dimension_key(&self) -> Key {
+        Key::digest(&["dimension", &self.backends[0].emb_name()])
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OllamaEmbRequest {
-    model: String,
-    prompt: String,
-}
+    // Dimension of the last answer of the embedding model. It is kept apart from the
+    // vectors, so eviction and purges of the embedding namespace don't lose it.
+    fn emb_dimension(&self, cache: &Cache) -> Option<usize> {
+        cache.get_meta(&self.emb_dimension_key())
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OllamaEmbResponse {
-    embedding: Vec<f32>,
-}
+    // The model may answer with vectors of another size than before, e.g. a model tag
+    // was pulled again with another model behind it
+    fn update_emb_dimension(&self, cache: &Cache, dimension: usize) {
+        let old = self.emb_dimension(cache);
+        if old == Some(dimension) {
+            return;
+        }
+        if let Some(old) = old {
+            println!(
+                "Embedding dimension of {} changed from {} to {}, cached embeddings are rebuilt",
+                self.backends[0].emb_name(),
+                old,
+                dimension
+            );
+        }
+        cache.set_meta(self.emb_dimension_key(), &dimension);
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIChatRequest {
-    model: String,
-    messages: Vec<ChatMessage>,
-    max_tokens: i32,
-    temperature: f32,
-    stop: Option<Vec<String>>,
-}
+    fn emb_request(
+        &self,
+        backend: &dyn LlmBackend,
+        texts: &[String],
+    ) -> Result<Vec<Vec<f32>>, LlmError> {
+        let estimator = Estimator::for_model(&backend.emb_name());
+        let estimated_tokens = texts
+            .iter()
+            .map(|text| estimator.tokens(text))
+            .sum::<usize>() as i64;
+        self.usage
+            .check_budget(&self.budget, &backend.emb_name(), estimated_tokens, 0)
+            .map_err(LlmError::BudgetExceeded)?;
+        let start = Instant::now();
+        let response = self.retry.run(|| backend.emb_batch(texts))?;
+        if response.embeddings.len() != texts.len() {
+            return Err(LlmError::Decode(format!(
+                "{} embeddings for {} texts",
+                response.embeddings.len(),
+                texts.len()
+            )));
+        }
+        // Replayed fixtures and some local servers tell no usage
+        let prompt_tokens = response
+            .usage
+            .map_or(estimated_tokens, |usage| usage.prompt_tokens);
+        self.usage.record(UsageRecord {
+            phase: "embedding".to_string(),
+            model: backend.emb_name(),
+            prompt_tokens,
+            completion_tokens: 0,
+            latency: start.elapsed(),
+            cached: false,
+        });
+        Ok(response.embeddings)
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ChatMessage {
-    role: String, // e.g., "user", "assistant", "system"
-    content: String,
+    fn record_cached(&self, phase: &str, model: String) {
+        self.usage.record(UsageRecord {
+            phase: phase.to_string(),
+            model,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            latency: Duration::ZERO,
+            cached: true,
+        });
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIChatResponse {
-    id: String,
-    object: String,
-    created: i64,
-    choices: Vec<OpenAIChatChoice>,
-    usage: OpenAIUsage,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockResponse, MockServer};
+    use std::sync::Arc;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIChatChoice {
-    index: i32,
-    message: ChatMessage, // Changed to include the message object
-    finish_reason: Option<String>,
-}
+    struct EchoBackend(&'static str);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIUsage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
-}
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIEmbRequest {
-    model: String,
-    input: String,
-}
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIEmbResponse {
-    data: Vec<OpenAIEmbData>,
-}
+    impl LlmBackend for EchoBackend {
+        fn name(&self) -> String {
+            self.0.to_string()
+        }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIEmbData {
-    embedding: Vec<f32>,
+        fn emb_name(&self) -> String {
+            self.0.to_string()
+        }
+
+        fn chat(&self, _request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+            Ok(ChatResponse {
+                content: self.0.to_string(),
+                usage: None,
+            })
+        }
+
+        fn emb(&self, _content: &str) -> Result<Vec<f32>, LlmError> {
+            Ok(vec![self.0.len() as f32])
+        }
+    }
+
+    #[test]
+    fn test_escalate() {
+        let llm = LLMApi::new(Box::new(EchoBackend("small")))
+            .with_fallback(Box::new(EchoBackend("large")));
+        let cache = Cache::in_memory();
+        let ask = |model| {
+            llm.chat_with(model, "generate", vec![], None, Sampling::default(), &cache)
+                .unwrap()
+        };
+        assert_eq!(ask(0), "small");
+        assert_eq!(llm.escalate(0), Some((1, "large".to_string())));
+        assert_eq!(ask(1), "large");
+        assert_eq!(llm.escalate(1), None);
+        // Every caller escalates on its own, the main model stays for the others
+        assert_eq!(ask(0), "small");
+        // Embeddings must stay comparable with the ones computed before
+        assert_eq!(llm.emb("text", &cache, "text").unwrap(), vec![5.0]);
+    }
+
+    #[test]
+    fn test_budget_without_usage() {
+        let llm = LLMApi::new(Box::new(EchoBackend("small"))).with_budget(Budget::new(
+            Some(MAX_TOKENS as i64 + 1),
+            None,
+            None,
+        ));
+        let cache = Cache::in_memory();
+        let ask = |question: &str| llm.chat("ask", vec![ChatMessage::user(question)], None, &cache);
+        // One token of "?" and the longest answer fit exactly
+        assert_eq!(ask("?").unwrap(), "small");
+        // EchoBackend tells no usage, the estimated tokens of the first call count
+        assert!(llm.usage().total_tokens() > 0);
+        assert!(matches!(ask("!"), Err(LlmError::BudgetExceeded(_))));
+    }
+
+    #[test]
+    fn test_emb_batch() {
+        let llm = LLMApi::new(Box::new(EchoBackend("emb"))).with_emb_batch(2, 2);
+        let cache = Cache::in_memory();
+        llm.emb("cached", &cache, "cached").unwrap();
+        let texts = ["a", "cached", "bb", "ccc", "dddd"].map(String::from);
+        let progress = Mutex::new(vec![]);
+        let embeddings = llm
+            .emb_batch(&texts, &cache, &|ready, all| {
+                progress.lock().unwrap().push((ready, all))
+            })
+            .unwrap();
+        // EchoBackend embeds everything as the length of its name
+        assert_eq!(embeddings.len(), 5);
+        let progress = progress.into_inner().unwrap();
+        assert_eq!(progress.first(), Some(&(1, 5)));
+        assert_eq!(progress.last(), Some(&(5, 5)));
+        let requests = llm
+            .usage()
+            .records()
+            .into_iter()
+            .filter(|record| !record.cached)
+            .count();
+        // One single request, then 4 texts in 2 batches
+        assert_eq!(requests, 3);
+        // EchoBackend tells no usage, the tokens are estimated
+        let tokens = llm.usage().total_tokens();
+        assert_eq!(tokens, 2 + 1 + 1 + 1 + 2);
+    }
+
+    // Embeds every text as a vector of the current dimension
+    struct DimensionBackend(Arc<AtomicUsize>);
+
+    impl LlmBackend for DimensionBackend {
+        fn name(&self) -> String {
+            "dimension".to_string()
+        }
+
+        fn emb_name(&self) -> String {
+            "dimension".to_string()
+        }
+
+        fn chat(&self, _request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+            Err(LlmError::Unsupported("no chat".to_string()))
+        }
+
+        fn emb(&self, _content: &str) -> Result<Vec<f32>, LlmError> {
+            Ok(vec![0.5; self.0.load(Ordering::SeqCst)])
+        }
+    }
+
+    #[test]
+    fn test_emb_cache_keys() {
+        let cache = Cache::in_memory();
+        let small = LLMApi::new(Box::new(EchoBackend("small")));
+        let large = LLMApi::new(Box::new(EchoBackend("large model")));
+        assert_eq!(small.emb("text", &cache, "text").unwrap(), vec![5.0]);
+        assert_eq!(large.emb("text", &cache, "text").unwrap(), vec![11.0]);
+
+        let dimension = Arc::new(AtomicUsize::new(2));
+        let llm = LLMApi::new(Box::new(DimensionBackend(dimension.clone())));
+        let texts = ["a", "b"].map(String::from);
+        llm.emb_batch(&texts, &cache, &|_, _| {}).unwrap();
+        dimension.store(3, Ordering::SeqCst);
+        let texts = ["a", "c"].map(String::from);
+        let embeddings = llm.emb_batch(&texts, &cache, &|_, _| {}).unwrap();
+        assert_eq!(embeddings, vec![vec![0.5; 3], vec![0.5; 3]]);
+        assert_eq!(llm.emb("b", &cache, "b").unwrap().len(), 3);
+
+        // The dimension is not one of the entries, a purge of the vectors keeps it
+        let entries = cache.list(Some(Namespace::Embedding));
+        assert!(entries.iter().all(|entry| entry.preview.starts_with('[')));
+        cache.purge(Some(Namespace::Embedding), None);
+        assert_eq!(llm.emb_dimension(&cache), Some(3));
+        cache.set(Namespace::Embedding, llm.emb_key(3, "a"), &vec![1.0f32; 3]);
+        cache.set(Namespace::Embedding, llm.emb_key(2, "d"), &vec![1.0f32; 2]);
+        let texts = ["a", "d"].map(String::from);
+        let embeddings = llm.emb_batch(&texts, &cache, &|_, _| {}).unwrap();
+        assert_eq!(embeddings, vec![vec![1.0; 3], vec![0.5; 3]]);
+    }
+
+    #[test]
+    fn test_send_retry_after() {
+        let server = MockServer::start(vec![
+            MockResponse {
+                status: 429,
+                headers: vec![("Retry-After".to_string(), "2".to_string())],
+                body: String::new(),
+            },
+            MockResponse {
+                status: 503,
+                headers: vec![],
+                body: "busy".to_string(),
+            },
+        ]);
+        let client = reqwest::blocking::Client::new();
+        let result = send(client.get(format!("{}/api", server.url)));
+        assert!(matches!(
+            result,
+            Err(LlmError::RateLimited {
+                retry_after: Some(retry_after)
+            }) if retry_after == Duration::from_secs(2)
+        ));
+        let result = send(client.get(format!("{}/api", server.url)));
+        assert!(matches!(
+            result,
+            Err(LlmError::Http {
+                status: 503,
+                retry_after: None,
+                ..
+            })
+        ));
+    }
 }
@@ -0,0 +1,716 @@
+use crate::config::{OllamaApi, OllamaConfig};
+use crate::llm_api::{
+    read_json, send, ChatMessage, ChatRequest, ChatResponse, EmbResponse, LlmBackend, LlmError,
+    Usage,
+};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Once;
+use std::time::Duration;
+
+pub const OLLAMA_URL: &str = "http://127.0.0.1:11434";
+const OLLAMA_PORT: u16 = 11434;
+
+pub struct OllamaBackend {
+    base_url: String,
+    model: String,
+    emb: String,
+    stream: bool,
+    config: OllamaConfig,
+    client: Client,
+    // Printed once per session, the rewrite loop sends conversations on every attempt
+    roles_warning: Once,
+}
+
+impl OllamaBackend {
+    pub fn new(
+        base_url: &str,
+        model: String,
+        emb: String,
+        stream: bool,
+        config: OllamaConfig,
+    ) -> OllamaBackend {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(60 * 10))
+            .build()
+            .unwrap();
+        // OLLAMA_HOST is often set as "host" or "host:port", like the Ollama CLI the port
+        // defaults to 11434 then
+        let base_url = base_url.trim_end_matches('/');
+        let base_url = match base_url.contains("://") {
+            true => base_url.to_string(),
+            false => {
+                let (host, path) = base_url.split_at(base_url.find('/').unwrap_or(base_url.len()));
+                match host.rsplit_once(':') {
+                    Some((_, port)) if !port.is_empty() && !port.contains(']') => {
+                        format!("http://{}{}", host, path)
+                    }
+                    _ => format!("http://{}:{}{}", host, OLLAMA_PORT, path),
+                }
+            }
+        };
+        OllamaBackend {
+            base_url,
+            model,
+            emb,
+            stream,
+            config,
+            client,
+            roles_warning: Once::new(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Asks the server for its version, so an unreachable server is reported before the session.
+    pub fn check_server(&self) -> Result<String, LlmError> {
+        let request = self
+            .client
+            .get(self.url("/api/version"))
+            .timeout(Duration::from_secs(5));
+        let response = read_json::<OllamaVersion>(send(request)?)?;
+        Ok(response.version)
+    }
+
+    /// Models of `models` which are not pulled to the server yet.
+    pub fn missing_models(&self, models: &[&str]) -> Result<Vec<String>, LlmError> {
+        let response = send(self.client.get(self.url("/api/tags")))?;
+        let tags = read_json::<OllamaTags>(response)?;
+        let available = tags
+            .models
+            .iter()
+            .map(|m| with_tag(&m.name))
+            .collect::<Vec<_>>();
+        Ok(models
+            .iter()
+            .filter(|model| !available.contains(&with_tag(model)))
+            .map(|model| model.to_string())
+            .collect())
+    }
+
+    fn legacy_emb(&self, content: &str) -> Result<Vec<f32>, LlmError> {
+        let request = OllamaEmbRequest {
+            model: self.emb.to_string(),
+            prompt: content.to_string(),
+        };
+        let response = send(self.client.post(self.url("/api/embeddings")).json(&request))?;
+        let response = read_json::<OllamaEmbResponse>(response)?;
+        Ok(response.embedding)
+    }
+
+    pub fn pull(&self, model: &str) -> Result<(), String> {
+        let request = OllamaPullRequest {
+            model: model.to_string(),
+            stream: true,
+        };
+        // Downloads of big models take longer than the timeout of generation requests
+        let request = self
+            .client
+            .post(self.url("/api/pull"))
+            .json(&request)
+            .timeout(Duration::from_secs(60 * 60 * 6));
+        let response = send(request).map_err(|err| err.to_string())?;
+        read_pull_progress(BufReader::new(response), &mut std::io::stdout())
+    }
+}
+
+// "bge-large" and "bge-large:latest" are the same model
+fn with_tag(model: &str) -> String {
+    if model.contains(':') {
+        model.to_string()
+    } else {
+        format!("{}:latest", model)
+    }
+}
+
+/// Prints the NDJSON status of /api/pull, download statuses as percents in one line.
+fn read_pull_progress(reader: impl BufRead, out: &mut impl Write) -> Result<(), String> {
+    let mut last_status = String::new();
+    for line in reader.lines() {
+        let line = line.map_err(|err| err.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let progress =
+            serde_json::from_str::<OllamaPullProgress>(&line).map_err(|err| err.to_string())?;
+        if let Some(error) = progress.error {
+            return Err(error);
+        }
+        let status = progress.status.unwrap_or_default();
+        let mut line = String::new();
+        if status != last_status && !last_status.is_empty() {
+            line.push('\n');
+        }
+        match (progress.completed, progress.total) {
+            (Some(completed), Some(total)) if total > 0 => {
+                line.push_str(&format!("\r{} {}%", status, completed * 100 / total))
+            }
+            _ if status != last_status => line.push_str(&status),
+            _ => {}
+        }
+        write!(out, "{}", line)
+            .and_then(|_| out.flush())
+            .map_err(|err| err.to_string())?;
+        if status == "success" {
+            writeln!(out).map_err(|err| err.to_string())?;
+            return Ok(());
+        }
+        last_status = status;
+    }
+    Err("Pull of the model is interrupted".to_string())
+}
+
+impl LlmBackend for OllamaBackend {
+    fn name(&self) -> String {
+        format!("ollama:{}", self.model)
+    }
+
+    fn emb_name(&self) -> String {
+        format!("ollama:{}", self.emb)
+    }
+
+    fn cache_name(&self) -> String {
+        // Sampling options and format change the answer, the endpoint and keep_alive do not
+        let options = OllamaConfig {
+            url: None,
+            api: None,
+            keep_alive: None,
+            ..self.config.clone()
+        };
+        match serde_json::to_string(&options).unwrap().as_str() {
+            "{}" => self.name(),
+            options => format!("{}:{}", self.name(), options),
+        }
+    }
+
+    // Without num_ctx the window depends on the server version, OLLAMA_CONTEXT_LENGTH and the
+    // Modelfile, so it is left unknown instead of guessed
+    fn context_window(&self) -> Option<usize> {
+        self.config.num_ctx.map(|num_ctx| num_ctx as usize)
+    }
+
+    fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        let options = OllamaOptions {
+            num_predict: request.max_tokens,
+            stop: request.stop.clone(),
+            num_ctx: self.config.num_ctx,
+            temperature: request.sampling.temperature.or(self.config.temperature),
+            top_p: self.config.top_p,
+            seed: request.sampling.seed.or(self.config.seed),
+        };
+        // Without explicit choice a single prompt goes to /api/generate and a
+        // conversation with roles to /api/chat
+        let single_prompt = request.messages.len() == 1 && request.messages[0].role == "user";
+        let api = self.config.api.unwrap_or(if single_prompt {
+            OllamaApi::Generate
+        } else {
+            OllamaApi::Chat
+        });
+        let response = match api {
+            OllamaApi::Generate => {
+                // /api/generate takes the system prompt apart and puts it in the template
+                // of the model, the other roles can't be told apart in a single prompt
+                let (system, messages): (Vec<&ChatMessage>, Vec<&ChatMessage>) =
+                    request.messages.iter().partition(|m| m.role == "system");
+                if messages.iter().any(|m| m.role != "user") {
+                    self.roles_warning.call_once(|| {
+                        println!(
+                            "Warning: Ollama \"api\": \"generate\" has no roles, the answers of the model are sent as a part of the prompt"
+                        )
+                    });
+                }
+                let request = OllamaRequest {
+                    // model: "qwen2.5-coder:7b".to_string(), // smart model but slow
+                    // model: "qwen2.5-coder:1.5b".to_string(), // smart model but slow
+                    model: self.model.to_string(),
+                    // model: "gemma2:2b".to_string(), // fast but very stupid model - excellent for fast testing
+                    //  model: "gemma2".to_string(), // medium model
+                    prompt: messages
+                        .iter()
+                        .map(|m| m.content.as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    system: match system.is_empty() {
+                        true => None,
+                        false => Some(
+                            system
+                                .iter()
+                                .map(|m| m.content.as_str())
+                                .collect::<Vec<_>>()
+                                .join("\n"),
+                        ),
+                    },
+                    stream: self.stream,
+                    options,
+                    keep_alive: self.config.keep_alive.clone(),
+                    format: request.schema.clone().or(self.config.format.clone()),
+                };
+                send(self.client.post(self.url("/api/generate")).json(&request))?
+            }
+            OllamaApi::Chat => {
+                let request = OllamaChatRequest {
+                    model: self.model.to_string(),
+                    messages: request.messages.clone(),
+                    stream: self.stream,
+                    options,
+                    keep_alive: self.config.keep_alive.clone(),
+                    format: request.schema.clone().or(self.config.format.clone()),
+                };
+                send(self.client.post(self.url("/api/chat")).json(&request))?
+            }
+        };
+        if self.stream {
+            return read_stream(BufReader::new(response), &mut std::io::stdout());
+        }
+        let response = read_json::<OllamaResponse>(response)?;
+        Ok(ChatResponse {
+            usage: Some(response.usage()),
+            content: response.text().to_string(),
+        })
+    }
+
+    // /api/embed normalizes the vectors and /api/embeddings does not, so a single text
+    // goes the same way as a batch to get vectors comparable with the cached ones
+    fn emb(&self, content: &str) -> Result<Vec<f32>, LlmError> {
+        let mut embeddings = self.emb_batch(&[content.to_string()])?.embeddings;
+        if embeddings.is_empty() {
+            return Err(LlmError::Decode("no embedding in response".to_string()));
+        }
+        Ok(embeddings.swap_remove(0))
+    }
+
+    fn emb_batch(&self, contents: &[String]) -> Result<EmbResponse, LlmError> {
+        let request = OllamaEmbedRequest {
+            model: self.emb.to_string(),
+            input: contents.to_vec(),
+        };
+        match send(self.client.post(self.url("/api/embed")).json(&request)) {
+            Ok(response) => {
+                let response = read_json::<OllamaEmbedResponse>(response)?;
+                Ok(EmbResponse {
+                    embeddings: response.embeddings,
+                    usage: response.prompt_eval_count.map(|tokens| Usage {
+                        prompt_tokens: tokens as i64,
+                        completion_tokens: 0,
+                    }),
+                })
+            }
+            // Ollama before 0.3 knows only /api/embeddings with a single text
+            Err(LlmError::Http { status: 404, .. }) => Ok(EmbResponse {
+                embeddings: contents
+                    .iter()
+                    .map(|content| self.legacy_emb(content))
+                    .collect::<Result<_, _>>()?,
+                usage: None,
+            }),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Collects NDJSON chunks of a streamed response, echoing tokens to `out` as they arrive.
+fn read_stream(reader: impl BufRead, out: &mut impl Write) -> Result<ChatResponse, LlmError> {
+    let mut content = String::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chunk = serde_json::from_str::<OllamaResponse>(&line)?;
+        content.push_str(chunk.text());
+        write!(out, "{}", chunk.text())?;
+        out.flush()?;
+        if chunk.done {
+            writeln!(out)?;
+            return Ok(ChatResponse {
+                content,
+                usage: Some(chunk.usage()),
+            });
+        }
+    }
+    // Without the final "done" chunk the answer is incomplete and must not be cached
+    Err(LlmError::Transport("LLM stream interrupted".to_string()))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaRequest {
+    model: String,
+    prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    stream: bool,
+    options: OllamaOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    options: OllamaOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaOptions {
+    num_predict: i32,
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+
+// Both a complete answer and a chunk of a streamed one, of /api/generate (`response`)
+// or /api/chat (`message`). Newer Ollama versions may omit `context` and `done_reason`,
+// and only the last chunk carries the statistics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaResponse {
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    created_at: String,
+    #[serde(default)]
+    response: String,
+    message: Option<ChatMessage>,
+    done: bool,
+    done_reason: Option<String>,
+    context: Option<Vec<i64>>,
+    total_duration: Option<i64>,
+    load_duration: Option<i64>,
+    prompt_eval_count: Option<i32>,
+    prompt_eval_duration: Option<i64>,
+    eval_count: Option<i32>,
+    eval_duration: Option<i64>,
+}
+
+impl OllamaResponse {
+    fn text(&self) -> &str {
+        match &self.message {
+            Some(message) => &message.content,
+            None => &self.response,
+        }
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            prompt_tokens: self.prompt_eval_count.unwrap_or_default() as i64,
+            completion_tokens: self.eval_count.unwrap_or_default() as i64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaVersion {
+    version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaTags {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaModel {
+    name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaPullRequest {
+    model: String,
+    stream: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaPullProgress {
+    status: Option<String>,
+    total: Option<u64>,
+    completed: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaEmbRequest {
+    model: String,
+    prompt: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaEmbResponse {
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaEmbedRequest {
+    model: String,
+    input: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaEmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+    prompt_eval_count: Option<i32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_api::{ChatMessage, Sampling};
+    use crate::mock_server::{MockResponse, MockServer};
+
+    #[test]
+    fn test_custom_url() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, r#"{"version":"0.3.14"}"#),
+            MockResponse::json(
+                200,
+                r#"{"model":"m","created_at":"t","response":"fn solution()","done":true}"#,
+            ),
+        ]);
+        let backend = OllamaBackend::new(
+            &format!("{}/", server.url),
+            "m".to_string(),
+            "e".to_string(),
+            false,
+            OllamaConfig::default(),
+        );
+        assert_eq!(backend.check_server().unwrap(), "0.3.14");
+        let response = backend
+            .chat(&ChatRequest {
+                messages: vec![ChatMessage::user("Write solution")],
+                max_tokens: 100,
+                stop: vec![],
+                schema: None,
+                phase: String::new(),
+                sampling: Sampling {
+                    temperature: None,
+                    seed: Some(7),
+                },
+            })
+            .unwrap();
+        assert_eq!(response.content, "fn solution()");
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/api/version");
+        assert_eq!(requests[1].path, "/api/generate");
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(body["options"]["seed"], 7);
+
+        let backend = OllamaBackend::new(
+            "gpu-box:11434",
+            "m".to_string(),
+            "e".to_string(),
+            false,
+            OllamaConfig::default(),
+        );
+        assert_eq!(backend.url("/api/tags"), "http://gpu-box:11434/api/tags");
+
+        for (host, url) in [
+            ("0.0.0.0", "http://0.0.0.0:11434"),
+            ("gpu-box:8080", "http://gpu-box:8080"),
+            ("[::1]", "http://[::1]:11434"),
+            ("[::1]:8080/", "http://[::1]:8080"),
+            ("https://ollama.example.com", "https://ollama.example.com"),
+        ] {
+            let backend = OllamaBackend::new(
+                host,
+                "m".to_string(),
+                "e".to_string(),
+                false,
+                OllamaConfig::default(),
+            );
+            assert_eq!(backend.url(""), url);
+        }
+    }
+
+    #[test]
+    fn test_generate_api_with_roles() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"model":"m","created_at":"t","response":"fixed","done":true}"#,
+        )]);
+        let backend = OllamaBackend::new(
+            &server.url,
+            "m".to_string(),
+            "e".to_string(),
+            false,
+            OllamaConfig {
+                api: Some(OllamaApi::Generate),
+                ..OllamaConfig::default()
+            },
+        );
+        backend
+            .chat(&ChatRequest {
+                messages: vec![
+                    ChatMessage::system("You are a Rust developer"),
+                    ChatMessage::user("Write solution"),
+                    ChatMessage::assistant("fn solution()"),
+                    ChatMessage::user("Fix it"),
+                ],
+                max_tokens: 100,
+                stop: vec![],
+                schema: None,
+                phase: String::new(),
+                sampling: Sampling::default(),
+            })
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/api/generate");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["system"], "You are a Rust developer");
+        assert_eq!(body["prompt"], "Write solution\nfn solution()\nFix it");
+    }
+
+    #[test]
+    fn test_read_stream() {
+        let body = r#"{"model":"m","created_at":"t","response":"fn ","done":false}
+{"model":"m","created_at":"t","response":"solution()","done":false}
+{"model":"m","created_at":"t","response":"","done":true,"done_reason":"stop","prompt_eval_count":10,"eval_count":2}
+"#;
+        let mut out = Vec::new();
+        let response = read_stream(body.as_bytes(), &mut out).unwrap();
+        assert_eq!(response.content, "fn solution()");
+        assert_eq!(String::from_utf8(out).unwrap(), "fn solution()\n");
+        assert_eq!(
+            response.usage,
+            Some(Usage {
+                prompt_tokens: 10,
+                completion_tokens: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_read_chat_stream() {
+        let body = r#"{"model":"m","created_at":"t","message":{"role":"assistant","content":"fn "},"done":false}
+{"model":"m","created_at":"t","message":{"role":"assistant","content":"solution()"},"done":false}
+{"model":"m","created_at":"t","message":{"role":"assistant","content":""},"done":true,"prompt_eval_count":10,"eval_count":2}
+"#;
+        let response = read_stream(body.as_bytes(), &mut Vec::new()).unwrap();
+        assert_eq!(response.content, "fn solution()");
+    }
+
+    #[test]
+    fn test_emb_batch() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                r#"{"model":"e","embeddings":[[0.1],[0.2]],"prompt_eval_count":2}"#,
+            ),
+            MockResponse::json(404, r#"{"error":"404 page not found"}"#),
+            MockResponse::json(200, r#"{"embedding":[0.3]}"#),
+            MockResponse::json(200, r#"{"model":"e","embeddings":[[0.4]]}"#),
+        ]);
+        let backend = OllamaBackend::new(
+            &server.url,
+            "m".to_string(),
+            "e".to_string(),
+            false,
+            OllamaConfig::default(),
+        );
+        let texts = vec!["a".to_string(), "b".to_string()];
+        let response = backend.emb_batch(&texts).unwrap();
+        assert_eq!(response.embeddings, vec![vec![0.1], vec![0.2]]);
+        assert_eq!(response.usage.map(|usage| usage.prompt_tokens), Some(2));
+        // Older servers get the texts one by one
+        let response = backend.emb_batch(&texts[..1]).unwrap();
+        assert_eq!(response.embeddings, vec![vec![0.3]]);
+        // A single text is embedded the same way as a batch
+        assert_eq!(backend.emb("a").unwrap(), vec![0.4]);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/api/embed");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["input"], serde_json::json!(["a", "b"]));
+        assert_eq!(requests[2].path, "/api/embeddings");
+        assert_eq!(requests[3].path, "/api/embed");
+    }
+
+    #[test]
+    fn test_missing_models() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"models":[{"name":"qwen2.5-coder:7b","size":4683087332},{"name":"bge-large:latest"}]}"#,
+        )]);
+        let backend = OllamaBackend::new(
+            &server.url,
+            "m".to_string(),
+            "e".to_string(),
+            false,
+            OllamaConfig::default(),
+        );
+        let missing = backend
+            .missing_models(&["qwen2.5-coder:7b", "bge-large", "gemma2:2b"])
+            .unwrap();
+        assert_eq!(missing, vec!["gemma2:2b"]);
+        assert_eq!(server.requests()[0].path, "/api/tags");
+    }
+
+    #[test]
+    fn test_read_pull_progress() {
+        let body = r#"{"status":"pulling manifest"}
+{"status":"pulling 6a0746a1ec1a","digest":"sha256:6a07","total":1000,"completed":500}
+{"status":"pulling 6a0746a1ec1a","digest":"sha256:6a07","total":1000,"completed":1000}
+{"status":"verifying sha256 digest"}
+{"status":"success"}
+"#;
+        let mut out = Vec::new();
+        read_pull_progress(body.as_bytes(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "pulling manifest\n\rpulling 6a0746a1ec1a 50%\rpulling 6a0746a1ec1a 100%\nverifying sha256 digest\nsuccess\n"
+        );
+
+        let body = r#"{"error":"pull model manifest: file does not exist"}"#;
+        let result = read_pull_progress(body.as_bytes(), &mut Vec::new());
+        assert_eq!(
+            result,
+            Err("pull model manifest: file does not exist".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cache_name() {
+        let backend = |config| {
+            OllamaBackend::new(OLLAMA_URL, "m".to_string(), "e".to_string(), false, config)
+        };
+        let config = OllamaConfig {
+            api: Some(OllamaApi::Chat),
+            keep_alive: Some(Value::from("10m")),
+            ..OllamaConfig::default()
+        };
+        assert_eq!(backend(config).cache_name(), "ollama:m");
+        let config = OllamaConfig {
+            num_ctx: Some(8192),
+            seed: Some(1),
+            ..OllamaConfig::default()
+        };
+        assert_eq!(
+            backend(config).cache_name(),
+            r#"ollama:m:{"num_ctx":8192,"seed":1}"#
+        );
+    }
+
+    #[test]
+    fn test_read_stream_interrupted() {
+        let body = r#"{"model":"m","created_at":"t","response":"fn ","done":false}"#;
+        let response = read_stream(body.as_bytes(), &mut Vec::new());
+        assert!(matches!(response, Err(LlmError::Transport(_))));
+    }
+}
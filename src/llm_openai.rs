@@ -0,0 +1,457 @@
+use crate::llm_api::{
+    read_json, send, ChatMessage, ChatRequest, ChatResponse, EmbResponse, LlmBackend, LlmError,
+    Usage,
+};
+use reqwest::blocking::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::{BufRead, BufReader, Write};
+use std::time::Duration;
+
+pub const OPENAI_URL: &str = "https://api.openai.com/v1";
+
+/// Any server speaking the OpenAI protocol: OpenAI itself, llama.cpp server, vLLM, LM Studio...
+pub struct OpenAIBackend {
+    base_url: String,
+    model: String,
+    emb_model: String,
+    api_key: Option<String>,
+    stream: bool,
+    client: Client,
+}
+
+impl OpenAIBackend {
+    pub fn new(
+        base_url: &str,
+        model: String,
+        emb_model: String,
+        api_key: Option<String>,
+        stream: bool,
+    ) -> OpenAIBackend {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(60 * 5))
+            .build()
+            .unwrap();
+        OpenAIBackend {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model,
+            emb_model,
+            api_key,
+            stream,
+            client,
+        }
+    }
+
+    fn post(&self, path: &str) -> RequestBuilder {
+        let request = self.client.post(format!("{}{}", self.base_url, path));
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+
+    // Streamed tokens are written to `out`
+    fn chat_to(
+        &self,
+        request: &ChatRequest,
+        out: &mut impl Write,
+    ) -> Result<ChatResponse, LlmError> {
+        let request = OpenAIChatRequest {
+            model: self.model.to_string(),
+            messages: request.messages.clone(),
+            max_tokens: request.max_tokens,
+            temperature: request.sampling.temperature.unwrap_or(0.7),
+            seed: request.sampling.seed,
+            // Some OpenAI-compatible servers reject an empty stop list
+            stop: if request.stop.is_empty() {
+                None
+            } else {
+                Some(request.stop.clone())
+            },
+            stream: self.stream,
+            stream_options: if self.stream {
+                Some(OpenAIStreamOptions {
+                    include_usage: true,
+                })
+            } else {
+                None
+            },
+            response_format: request.schema.as_ref().map(|schema| {
+                json!({
+                    "type": "json_schema",
+                    "json_schema": {"name": "project", "schema": schema, "strict": true},
+                })
+            }),
+        };
+
+        let response = send(self.post("/chat/completions").json(&request))?;
+        if self.stream {
+            return read_stream(BufReader::new(response), out);
+        }
+        let response = read_json::<OpenAIChatResponse>(response)?;
+
+        // Extract the assistant's reply from the first choice
+        let content = response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content)
+            .unwrap_or_default();
+        Ok(ChatResponse {
+            content,
+            usage: response.usage.map(|usage| Usage {
+                prompt_tokens: usage.prompt_tokens as i64,
+                completion_tokens: usage.completion_tokens as i64,
+            }),
+        })
+    }
+}
+
+impl LlmBackend for OpenAIBackend {
+    fn name(&self) -> String {
+        format!("openai:{}", self.model)
+    }
+
+    fn emb_name(&self) -> String {
+        format!("openai:{}", self.emb_model)
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        context_window(&self.model)
+    }
+
+    fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        self.chat_to(request, &mut std::io::stdout())
+    }
+
+    fn emb(&self, content: &str) -> Result<Vec<f32>, LlmError> {
+        let mut embeddings = self.emb_batch(&[content.to_string()])?.embeddings;
+        if embeddings.is_empty() {
+            return Err(LlmError::Decode("no embedding in response".to_string()));
+        }
+        Ok(embeddings.swap_remove(0))
+    }
+
+    fn emb_batch(&self, contents: &[String]) -> Result<EmbResponse, LlmError> {
+        let request = OpenAIEmbRequest {
+            model: self.emb_model.to_string(),
+            input: contents.to_vec(),
+        };
+
+        let response = send(self.post("/embeddings").json(&request))?;
+        let mut response = read_json::<OpenAIEmbResponse>(response)?;
+        // The order of the input is promised by "index", not by the order of the data
+        response.data.sort_by_key(|data| data.index);
+        Ok(EmbResponse {
+            embeddings: response
+                .data
+                .into_iter()
+                .map(|data| data.embedding)
+                .collect(),
+            usage: response.usage.map(|usage| Usage {
+                prompt_tokens: usage.prompt_tokens as i64,
+                completion_tokens: 0,
+            }),
+        })
+    }
+}
+
+// Models of local servers are unknown, their window is set at the server start
+fn context_window(model: &str) -> Option<usize> {
+    if model.starts_with("gpt-4o")
+        || model.starts_with("gpt-4.1")
+        || model.starts_with("gpt-4-turbo")
+        || model.starts_with("o1")
+        || model.starts_with("o3")
+        || model.starts_with("o4")
+    {
+        Some(128_000)
+    } else if model.starts_with("gpt-4") {
+        Some(8_192)
+    } else if model.starts_with("gpt-3.5-turbo") {
+        Some(16_385)
+    } else {
+        None
+    }
+}
+
+/// Collects server-sent events of a streamed response, echoing tokens to `out` as they arrive.
+fn read_stream(reader: impl BufRead, out: &mut impl Write) -> Result<ChatResponse, LlmError> {
+    let mut content = String::new();
+    let mut usage = None;
+    for line in reader.lines() {
+        let line = line?;
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None => continue,
+        };
+        if data == "[DONE]" {
+            writeln!(out)?;
+            return Ok(ChatResponse { content, usage });
+        }
+        let chunk = serde_json::from_str::<OpenAIStreamChunk>(data)?;
+        if let Some(delta) = chunk
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.delta.content)
+        {
+            content.push_str(&delta);
+            write!(out, "{}", delta)?;
+            out.flush()?;
+        }
+        if let Some(chunk_usage) = chunk.usage {
+            usage = Some(Usage {
+                prompt_tokens: chunk_usage.prompt_tokens as i64,
+                completion_tokens: chunk_usage.completion_tokens as i64,
+            });
+        }
+    }
+    // Without "[DONE]" the answer is incomplete and must not be cached
+    Err(LlmError::Transport("LLM stream interrupted".to_string()))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    max_tokens: i32,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<OpenAIStreamOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIStreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIStreamChunk {
+    #[serde(default)]
+    choices: Vec<OpenAIStreamChoice>,
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIStreamChoice {
+    delta: OpenAIDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIDelta {
+    content: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIChatResponse {
+    // Local OpenAI-compatible servers do not always fill in the metadata
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    object: String,
+    #[serde(default)]
+    created: i64,
+    choices: Vec<OpenAIChatChoice>,
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIChatChoice {
+    index: i32,
+    message: ChatMessage, // Changed to include the message object
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIUsage {
+    prompt_tokens: i32,
+    completion_tokens: i32,
+    total_tokens: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIEmbRequest {
+    model: String,
+    input: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIEmbResponse {
+    data: Vec<OpenAIEmbData>,
+    usage: Option<OpenAIEmbUsage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIEmbUsage {
+    prompt_tokens: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIEmbData {
+    #[serde(default)]
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_api::Sampling;
+    use crate::mock_server::{MockResponse, MockServer};
+
+    #[test]
+    fn test_chat_stream() {
+        let body = "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\"},\"finish_reason\":null}]}\n\n\
+data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"fn \"},\"finish_reason\":null}]}\n\n\
+data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"solution()\"},\"finish_reason\":\"stop\"}]}\n\n\
+data: {\"choices\":[],\"usage\":{\"prompt_tokens\":7,\"completion_tokens\":2,\"total_tokens\":9}}\n\n\
+data: [DONE]\n\n";
+        let server = MockServer::start(vec![MockResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/event-stream".to_string())],
+            body: body.to_string(),
+        }]);
+        let backend = OpenAIBackend::new(
+            &server.url,
+            "local-model".to_string(),
+            "local-emb".to_string(),
+            None,
+            true,
+        );
+        let mut out = Vec::new();
+        let response = backend
+            .chat_to(
+                &ChatRequest {
+                    messages: vec![ChatMessage::user("Write solution")],
+                    max_tokens: 100,
+                    stop: vec![],
+                    schema: None,
+                    phase: String::new(),
+                    sampling: Sampling::default(),
+                },
+                &mut out,
+            )
+            .unwrap();
+
+        assert_eq!(response.content, "fn solution()");
+        assert_eq!(String::from_utf8(out).unwrap(), "fn solution()\n");
+        assert_eq!(
+            response.usage,
+            Some(Usage {
+                prompt_tokens: 7,
+                completion_tokens: 2
+            })
+        );
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/chat/completions");
+        assert_eq!(requests[0].header("authorization"), None);
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["model"], "local-model");
+    }
+
+    #[test]
+    fn test_chat_http_error() {
+        let server = MockServer::start(vec![MockResponse::json(
+            401,
+            r#"{"error":{"message":"Incorrect API key provided"}}"#,
+        )]);
+        let backend = OpenAIBackend::new(
+            &server.url,
+            "gpt-4o".to_string(),
+            "text-embedding-ada-002".to_string(),
+            Some("wrong".to_string()),
+            false,
+        );
+        let response = backend.chat(&ChatRequest {
+            messages: vec![ChatMessage::user("Write solution")],
+            max_tokens: 100,
+            stop: vec![],
+            schema: None,
+            phase: String::new(),
+            sampling: Sampling::default(),
+        });
+        assert!(matches!(response, Err(LlmError::Http { status: 401, .. })));
+    }
+
+    #[test]
+    fn test_chat_json_schema() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"{\"solution_code\":\"fn solution() {}\"}"},"finish_reason":"stop"}]}"#,
+        )]);
+        let backend = OpenAIBackend::new(
+            &server.url,
+            "gpt-4o".to_string(),
+            "text-embedding-ada-002".to_string(),
+            None,
+            false,
+        );
+        let schema = json!({"type": "object", "properties": {"solution_code": {"type": "string"}}});
+        let response = backend
+            .chat(&ChatRequest {
+                messages: vec![ChatMessage::user("Write solution")],
+                max_tokens: 100,
+                stop: vec![],
+                schema: Some(schema.clone()),
+                phase: String::new(),
+                sampling: Sampling::default(),
+            })
+            .unwrap();
+        assert_eq!(response.content, r#"{"solution_code":"fn solution() {}"}"#);
+
+        let requests = server.requests();
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(body["response_format"]["json_schema"]["schema"], schema);
+    }
+
+    #[test]
+    fn test_emb_batch() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"object":"list","data":[{"object":"embedding","index":1,"embedding":[0.2]},{"object":"embedding","index":0,"embedding":[0.1]}],"usage":{"prompt_tokens":2,"total_tokens":2}}"#,
+        )]);
+        let backend = OpenAIBackend::new(
+            &server.url,
+            "gpt-4o".to_string(),
+            "text-embedding-3-small".to_string(),
+            None,
+            false,
+        );
+        let response = backend
+            .emb_batch(&["a".to_string(), "b".to_string()])
+            .unwrap();
+        assert_eq!(response.embeddings, vec![vec![0.1], vec![0.2]]);
+        assert_eq!(response.usage.map(|usage| usage.prompt_tokens), Some(2));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/embeddings");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["input"], json!(["a", "b"]));
+    }
+
+    #[test]
+    fn test_context_window() {
+        assert_eq!(context_window("gpt-4o-mini"), Some(128_000));
+        assert_eq!(context_window("gpt-4"), Some(8_192));
+        assert_eq!(context_window("gpt-3.5-turbo"), Some(16_385));
+        assert_eq!(context_window("qwen2.5-coder"), None);
+    }
+
+    #[test]
+    fn test_read_stream_interrupted() {
+        let body = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"fn \"},\"finish_reason\":null}]}\n\n";
+        let response = read_stream(body.as_bytes(), &mut Vec::new());
+        assert!(matches!(response, Err(LlmError::Transport(_))));
+    }
+}
@@ -33,6 +33,10 @@ impl Prompt {
         prompts
     }
 
+    pub fn contains(&self, key: &str) -> bool {
+        self.prompts.contains_key(key)
+    }
+
     pub fn create(&self, key: &str, params: &Vec<String>) -> String {
         let mut prompt = self.prompts.get(key).unwrap().clone();
         prompt = construct_prompt(&prompt, params);
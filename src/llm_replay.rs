@@ -0,0 +1,256 @@
+use crate::llm_api::{ChatRequest, ChatResponse, LlmBackend, LlmError};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Fixture files of a session: "generate_1.txt", "rewrite_1.txt", "rewrite_2.txt",
+// "embedding_1.json"... numbered by the order of calls of each prompt, whichever
+// model of the fallback chain answered
+struct Fixtures {
+    dir: PathBuf,
+    calls: Mutex<HashMap<String, u32>>,
+}
+
+impl Fixtures {
+    fn new(dir: &Path) -> Fixtures {
+        Fixtures {
+            dir: dir.to_path_buf(),
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn next(&self, phase: &str, extension: &str) -> PathBuf {
+        let mut calls = self.calls.lock().unwrap();
+        let attempt = calls.entry(phase.to_string()).or_insert(0);
+        *attempt += 1;
+        self.dir
+            .join(format!("{}_{}.{}", phase, attempt, extension))
+    }
+}
+
+/// Answers with responses recorded by `RecordBackend`, so a session runs without LLM.
+pub struct ReplayBackend {
+    fixtures: Arc<Fixtures>,
+    // Position in the fallback chain, models must differ in the cache keys
+    fallback: usize,
+}
+
+impl ReplayBackend {
+    pub fn new(dir: &Path) -> ReplayBackend {
+        ReplayBackend {
+            fixtures: Arc::new(Fixtures::new(dir)),
+            fallback: 0,
+        }
+    }
+
+    /// Backend for the `number`th model of the fallback chain, reading the same fixtures.
+    pub fn for_fallback(&self, number: usize) -> ReplayBackend {
+        ReplayBackend {
+            fixtures: self.fixtures.clone(),
+            fallback: number,
+        }
+    }
+}
+
+fn read_fixture(path: &Path) -> Result<String, LlmError> {
+    std::fs::read_to_string(path).map_err(|err| {
+        LlmError::Unsupported(format!("no recorded response {}: {}", path.display(), err))
+    })
+}
+
+impl LlmBackend for ReplayBackend {
+    fn name(&self) -> String {
+        match self.fallback {
+            0 => format!("replay:{}", self.fixtures.dir.display()),
+            number => format!("replay:{}#{}", self.fixtures.dir.display(), number),
+        }
+    }
+
+    fn emb_name(&self) -> String {
+        self.name()
+    }
+
+    fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        let content = read_fixture(&self.fixtures.next(&request.phase, "txt"))?;
+        Ok(ChatResponse {
+            content,
+            usage: None,
+        })
+    }
+
+    fn emb(&self, _content: &str) -> Result<Vec<f32>, LlmError> {
+        let content = read_fixture(&self.fixtures.next("embedding", "json"))?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
+
+/// Passes requests to another backend and saves its answers as fixtures of `ReplayBackend`.
+pub struct RecordBackend {
+    backend: Box<dyn LlmBackend>,
+    fixtures: Arc<Fixtures>,
+}
+
+impl RecordBackend {
+    pub fn new(dir: &Path, backend: Box<dyn LlmBackend>) -> RecordBackend {
+        std::fs::create_dir_all(dir).unwrap();
+        RecordBackend {
+            backend,
+            fixtures: Arc::new(Fixtures::new(dir)),
+        }
+    }
+
+    /// Records `backend`, the next model of the fallback chain, to the same fixtures.
+    pub fn for_fallback(&self, backend: Box<dyn LlmBackend>) -> RecordBackend {
+        RecordBackend {
+            backend,
+            fixtures: self.fixtures.clone(),
+        }
+    }
+}
+
+impl LlmBackend for RecordBackend {
+    fn name(&self) -> String {
+        self.backend.name()
+    }
+
+    fn emb_name(&self) -> String {
+        self.backend.emb_name()
+    }
+
+    fn cache_name(&self) -> String {
+        self.backend.cache_name()
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        self.backend.context_window()
+    }
+
+    fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        let response = self.backend.chat(request)?;
+        std::fs::write(self.fixtures.next(&request.phase, "txt"), &response.content)?;
+        Ok(response)
+    }
+
+    fn emb(&self, content: &str) -> Result<Vec<f32>, LlmError> {
+        let response = self.backend.emb(content)?;
+        std::fs::write(
+            self.fixtures.next("embedding", "json"),
+            serde_json::to_string(&response)?,
+        )?;
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::llm_api::{LLMApi, Sampling};
+    use crate::llm_prompt::Prompt;
+    use crate::state_machine::{run_state_machine, Options};
+    use crate::Lang;
+
+    #[test]
+    fn test_record_and_replay() {
+        let dir = std::env::temp_dir().join(format!("rustsn_replay_{}", std::process::id()));
+        let request = |phase: &str| ChatRequest {
+            messages: vec![],
+            max_tokens: 100,
+            stop: vec![],
+            schema: None,
+            phase: phase.to_string(),
+            sampling: Sampling::default(),
+        };
+
+        let recorder = RecordBackend::new(
+            &dir,
+            Box::new(ReplayBackend::new(Path::new("test_data/replay/rust_add"))),
+        );
+        recorder.chat(&request("generate")).unwrap();
+        recorder.chat(&request("rewrite")).unwrap();
+        assert!(dir.join("generate_1.txt").exists());
+        assert!(dir.join("rewrite_1.txt").exists());
+
+        let replay = ReplayBackend::new(&dir);
+        let generated = replay.chat(&request("generate")).unwrap();
+        assert!(generated.content.contains("fn solution"));
+        assert!(matches!(
+            replay.chat(&request("generate")),
+            Err(LlmError::Unsupported(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn temp_sandbox(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rustsn_{}_{}", name, std::process::id()));
+        dir.display().to_string()
+    }
+
+    fn phases(llm: &LLMApi) -> Vec<String> {
+        llm.usage()
+            .records()
+            .into_iter()
+            .map(|record| record.phase)
+            .collect()
+    }
+
+    // The whole generate -> build -> rewrite loop, needs cargo. The first model answers
+    // without code and the next one of the chain writes a wrong solution first.
+    #[test]
+    fn test_state_machine_replay() {
+        let llm = LLMApi::new(Box::new(ReplayBackend::new(Path::new(
+            "test_data/replay/no_code",
+        ))))
+        .with_fallback(Box::new(ReplayBackend::new(Path::new(
+            "test_data/replay/rust_add",
+        ))));
+        let prompt = Prompt::new("prompt/rust.txt");
+        let cache = Cache::in_memory();
+        let options = Options {
+            sandbox: temp_sandbox("sandbox_replay"),
+            ..Options::default()
+        };
+        run_state_machine(
+            &Lang::Rust,
+            "add two numbers",
+            &prompt,
+            &cache,
+            &llm,
+            &options,
+        );
+
+        assert_eq!(phases(&llm), vec!["generate", "generate", "rewrite"]);
+        let code = std::fs::read_to_string(format!("{}/src/lib.rs", options.sandbox)).unwrap();
+        assert!(code.contains("a + b"));
+        std::fs::remove_dir_all(&options.sandbox).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_candidates_replay() {
+        let llm = LLMApi::new(Box::new(ReplayBackend::new(Path::new(
+            "test_data/replay/rust_add_parallel",
+        ))));
+        let prompt = Prompt::new("prompt/rust.txt");
+        let cache = Cache::in_memory();
+        let options = Options {
+            candidates: 2,
+            sandbox: temp_sandbox("sandbox_parallel"),
+            ..Options::default()
+        };
+        run_state_machine(
+            &Lang::Rust,
+            "add two numbers",
+            &prompt,
+            &cache,
+            &llm,
+            &options,
+        );
+
+        // Candidates differ in sampling, so the second one is not answered from the cache
+        assert_eq!(phases(&llm), vec!["generate", "generate"]);
+        let code = std::fs::read_to_string(format!("{}/src/lib.rs", options.sandbox)).unwrap();
+        assert!(code.contains("a + b"));
+        assert!(!Path::new(&format!("{}_1", options.sandbox)).exists());
+        std::fs::remove_dir_all(&options.sandbox).unwrap();
+    }
+}
@@ -1,8 +1,10 @@
 use regex::Regex;
+use serde::Deserialize;
+use serde_json::{json, Value};
 
 use crate::{utils::remove_comments, Lang};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Project {
     pub dependencies: String,
     pub additional_config: Vec<String>,
@@ -29,9 +31,154 @@ impl Default for Project {
     }
 }
 
+// Answer of the structured output mode, fields are named as in `Project`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ProjectJson {
+    dependencies: String,
+    additional_config: Vec<String>,
+    solution_code: String,
+    test_code: String,
+    install_dependency_command: Option<String>,
+    build_command: String,
+    test_command: String,
+}
+
+// Fields of `Project` which are used by the language and what they contain
+fn json_fields(lang: &Lang) -> Vec<(&'static str, &'static str)> {
+    match lang {
+        Lang::Rust => vec![
+            ("dependencies", "Contents of Cargo.toml file"),
+            ("solution_code", "Contents of src/lib.rs file with code and tests"),
+            ("build_command", "Command for building code with tests (with \"--tests\" parameter)"),
+            ("test_command", "Command for running tests"),
+        ],
+        Lang::Java => vec![
+            ("dependencies", "Contents of pom.xml file"),
+            ("solution_code", "Contents of src/main/java/com/example/solution/Solution.java file"),
+            ("test_code", "Contents of src/test/java/com/example/solution/SolutionTest.java file"),
+            ("build_command", "Command for compiling code with tests"),
+            ("test_command", "Command for running tests"),
+        ],
+        Lang::Kotlin => vec![
+            ("dependencies", "Contents of build.gradle file"),
+            ("solution_code", "Contents of src/main/kotlin/Solution.kt file"),
+            ("test_code", "Contents of src/test/kotlin/SolutionTest.kt file"),
+            ("build_command", "Command for compiling code with tests (use gradle command not gradlew)"),
+            ("test_command", "Command for running tests (use gradle command not gradlew)"),
+        ],
+        Lang::Scala => vec![
+            ("dependencies", "Contents of build.sbt file"),
+            ("solution_code", "Contents of src/main/scala/Solution.scala file"),
+            ("test_code", "Contents of src/test/scala/SolutionTest.scala file"),
+            ("build_command", "Command for compiling code with tests"),
+            ("test_command", "Command for running tests"),
+        ],
+        Lang::Swift => vec![
+            ("dependencies", "Contents of Package.swift file"),
+            ("solution_code", "Contents of Sources/Solution/Solution.swift file"),
+            ("test_code", "Contents of Tests/SolutionTests/SolutionTests.swift file"),
+            ("build_command", "Command for compiling code with tests"),
+            ("test_command", "Command for running tests"),
+        ],
+        Lang::Python => vec![
+            ("dependencies", "Contents of requirements.txt file"),
+            ("solution_code", "Contents of solution.py file"),
+            ("test_code", "Contents of test.py file"),
+            ("install_dependency_command", "Command for installing dependencies"),
+            ("test_command", "Command for running tests"),
+        ],
+        Lang::JavaScript => vec![
+            ("dependencies", "Contents of package.json file"),
+            ("solution_code", "Contents of src/solution.js file"),
+            ("test_code", "Contents of src/solution.test.js file"),
+            ("install_dependency_command", "Command for installing dependencies"),
+            ("test_command", "Command for running tests"),
+        ],
+        Lang::TypeScript => vec![
+            ("dependencies", "Contents of package.json file"),
+            ("additional_config", "Array with one element: contents of tsconfig.json file"),
+            ("solution_code", "Contents of src/solution.ts file"),
+            ("test_code", "Contents of src/solution.test.ts file"),
+            ("build_command", "Command for installing dependencies"),
+            ("test_command", "Command for running tests"),
+        ],
+        Lang::Php => vec![
+            ("dependencies", "Contents of composer.json file (project name \"sandbox/solution\")"),
+            ("solution_code", "Contents of src/Solution.php file (\"<?php\" at the beginning, namespace `Solution`)"),
+            ("test_code", "Contents of tests/SolutionTest.php file (\"<?php\" at the beginning)"),
+            ("install_dependency_command", "Command for installing dependencies"),
+            ("test_command", "Command for running tests"),
+        ],
+        _ => vec![],
+    }
+}
+
+impl Project {
+    /// JSON schema of the answer in structured output mode.
+    pub fn json_schema(lang: &Lang) -> Value {
+        let fields = json_fields(lang);
+        let properties = fields
+            .iter()
+            .map(|(name, description)| {
+                let property = match *name {
+                    "additional_config" => json!({
+                        "type": "array",
+                        "items": {"type": "string"},
+                        "description": description,
+                    }),
+                    _ => json!({"type": "string", "description": description}),
+                };
+                (name.to_string(), property)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": fields.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+            "additionalProperties": false,
+        })
+    }
+
+    /// Instruction to append to a prompt, so the model answers with JSON instead of markdown.
+    pub fn json_instruction(lang: &Lang) -> String {
+        let mut instruction = String::from(
+            "Formulate the solution as a single JSON object instead of the blocks above, without any text around it. Fields of the object:\n",
+        );
+        for (name, description) in json_fields(lang) {
+            instruction.push_str(&format!("- \"{}\": {}\n", name, description));
+        }
+        instruction
+    }
+}
+
 pub struct LLMResponse;
 
 impl LLMResponse {
+    /// Reads an answer of the structured output mode, None if it is not the expected JSON.
+    pub fn parse_json_response(response: &str, language: Lang) -> Option<Project> {
+        // Models sometimes wrap the object in a markdown code block
+        let start = response.find('{')?;
+        let end = response.rfind('}')?;
+        let json = serde_json::from_str::<ProjectJson>(response.get(start..=end)?).ok()?;
+        if json.solution_code.trim().is_empty() {
+            return None;
+        }
+        Some(Project {
+            dependencies: json.dependencies,
+            additional_config: json.additional_config,
+            solution_code: json.solution_code,
+            test_code: json.test_code,
+            // Commands are run as they are, models put comments in them in JSON too
+            install_dependency_command: json
+                .install_dependency_command
+                .map(|command| remove_comments(&command)),
+            build_command: remove_comments(&json.build_command),
+            test_command: remove_comments(&json.test_command),
+            lang: language,
+        })
+    }
+
     fn parse_positions(response: &str) -> Vec<(String, usize)> {
         let re_section =
             Regex::new(r"(?m)^(?:\s*(?:\#*)?\s*\*\*)?(?:\d+\.\s*)?(.*?)[:\*]*\*\*\s*$").unwrap();
@@ -885,7 +1032,6 @@ impl LLMResponse {
     }
 }
 
-
 mod tests {
     #[test]
     fn test_parse_llm_response_java() {
@@ -921,4 +1067,35 @@ mod tests {
             assert!(!project.test_command.is_empty());
         }
     }
+
+    #[test]
+    fn test_parse_json_response() {
+        let schema = crate::llm_response::Project::json_schema(&crate::Lang::Rust);
+        assert_eq!(
+            schema["required"],
+            serde_json::json!([
+                "dependencies",
+                "solution_code",
+                "build_command",
+                "test_command"
+            ])
+        );
+
+        let response = r##"```json
+{"dependencies": "[package]\nname = \"sandbox\"", "solution_code": "fn solution() {}", "build_command": "cargo build --tests\n", "test_command": "# Run tests\ncargo test"}
+```"##;
+        let project =
+            crate::llm_response::LLMResponse::parse_json_response(response, crate::Lang::Rust)
+                .unwrap();
+        assert_eq!(project.dependencies, "[package]\nname = \"sandbox\"");
+        assert_eq!(project.solution_code, "fn solution() {}");
+        assert_eq!(project.build_command, "cargo build --tests");
+        assert_eq!(project.test_command, "cargo test");
+
+        let response = "**Cargo.toml**\n```toml\n[package]\n```";
+        assert!(
+            crate::llm_response::LLMResponse::parse_json_response(response, crate::Lang::Rust)
+                .is_none()
+        );
+    }
 }
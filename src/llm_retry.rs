@@ -0,0 +1,170 @@
+use crate::llm_api::LlmError;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct RetryPolicy {
+    /// Retries of a single LLM call.
+    pub max_retries: u32,
+    /// Retries of all LLM calls of the session, so a batch run can not hang forever.
+    pub max_session_retries: u32,
+    pub base_delay: Duration,
+    /// Longest wait between two attempts. A Retry-After above it is not waited out.
+    pub max_delay: Duration,
+    session_retries: AtomicU32,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, max_session_retries: u32, max_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            max_session_retries,
+            base_delay: Duration::from_secs(1),
+            max_delay,
+            session_retries: AtomicU32::new(0),
+        }
+    }
+
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new(0, 0, Duration::ZERO)
+    }
+
+    /// Calls `call` until it succeeds, fails with a permanent error or retries run out.
+    pub fn run<T>(&self, mut call: impl FnMut() -> Result<T, LlmError>) -> Result<T, LlmError> {
+        let mut attempt = 0;
+        loop {
+            let err = match call() {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+            if attempt >= self.max_retries {
+                return Err(err);
+            }
+            let delay = match self.delay(&err, attempt) {
+                Some(delay) => delay,
+                None => return Err(err),
+            };
+            let session_retries = self.session_retries.fetch_add(1, Ordering::SeqCst);
+            if session_retries >= self.max_session_retries {
+                println!("Retry limit of the session is reached");
+                return Err(err);
+            }
+            attempt += 1;
+            println!(
+                "{}. Retry {}/{} in {:.1} s",
+                err,
+                attempt,
+                self.max_retries,
+                delay.as_secs_f32()
+            );
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Wait before the next attempt, or None if the error is permanent.
+    fn delay(&self, err: &LlmError, attempt: u32) -> Option<Duration> {
+        let retry_after = match err {
+            LlmError::Transport(_) => None,
+            LlmError::RateLimited { retry_after } => *retry_after,
+            LlmError::Http {
+                status,
+                retry_after,
+                ..
+            } if *status == 408 || *status >= 500 => *retry_after,
+            _ => return None,
+        };
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+
+    // Exponential backoff with jitter: a random delay between half and full of base * 2^attempt
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let random = RandomState::new().build_hasher().finish();
+        delay / 2 + delay.mul_f64((random % 1000) as f64 / 2000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_retries: u32, max_session_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::new(max_retries, max_session_retries, Duration::from_millis(10))
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_errors() {
+        let policy = policy(3, 10);
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            match calls {
+                1 => Err(LlmError::RateLimited {
+                    retry_after: Some(Duration::from_millis(1)),
+                }),
+                2 => Err(LlmError::Http {
+                    status: 503,
+                    body: String::new(),
+                    retry_after: None,
+                }),
+                _ => Ok("answer"),
+            }
+        });
+        assert_eq!(result.unwrap(), "answer");
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_retry_permanent_error() {
+        let policy = policy(3, 10);
+        let mut calls = 0;
+        let result: Result<(), LlmError> = policy.run(|| {
+            calls += 1;
+            Err(LlmError::Http {
+                status: 400,
+                body: String::new(),
+                retry_after: None,
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        // Retry-After longer than the maximum delay is not waited out
+        calls = 0;
+        let result: Result<(), LlmError> = policy.run(|| {
+            calls += 1;
+            Err(LlmError::RateLimited {
+                retry_after: Some(Duration::from_secs(3600)),
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_retry_session_limit() {
+        let policy = policy(3, 4);
+        let mut calls = 0;
+        for _ in 0..3 {
+            let result: Result<(), LlmError> = policy.run(|| {
+                calls += 1;
+                Err(LlmError::Transport("connection refused".to_string()))
+            });
+            assert!(result.is_err());
+        }
+        // 3 retries of the first call, 1 of the second and none of the third
+        assert_eq!(calls, 4 + 2 + 1);
+    }
+}
@@ -7,19 +7,31 @@ use std::sync::Mutex;
 
 mod build_tool;
 mod cache;
+mod config;
+mod conversation;
+mod credentials;
 mod file_explorer;
+mod llm_anthropic;
 mod llm_api;
+mod llm_ollama;
+mod llm_openai;
 mod llm_prompt;
+mod llm_replay;
 mod llm_response;
+mod llm_retry;
+#[cfg(test)]
+mod mock_server;
 mod state_machine;
+mod tokens;
+mod usage;
 mod utils;
 mod vector_utils;
 
 static VERBOSE: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
 
 const MAX_NUMBER_OF_ATTEMPTS: i32 = 5;
-const OLLAMA_API: &str = "http://127.0.0.1:11434/api/generate";
-const OLLAMA_EMB: &str = "http://127.0.0.1:11434/api/embeddings";
+// Tokens of the rewrite conversation before the oldest attempts are dropped
+const MAX_HISTORY_TOKENS: usize = 6000;
 
 fn main() {
     std::env::set_var("OLLAMA_NUM_PARALLEL", "2");
@@ -80,6 +92,289 @@ Usage:
                 .default_value("bge-large")
                 .global(true),
         )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .help("JSON config file, \"rustsn.json\" is read if it exists")
+                .global(true),
+        )
+        .arg(
+            Arg::new("ollama-url")
+                .long("ollama-url")
+                .value_name("URL")
+                .help("Ollama server, by default OLLAMA_HOST environment variable or http://127.0.0.1:11434")
+                .global(true),
+        )
+        .arg(
+            Arg::new("pull")
+                .long("pull")
+                .help("Pull Ollama models which are not on the server yet")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("ollama-api")
+                .long("ollama-api")
+                .value_name("API")
+                .help("Ollama endpoint, by default /api/generate for single prompts and /api/chat for conversations")
+                .global(true)
+                .value_parser(["generate", "chat"]),
+        )
+        .arg(
+            Arg::new("num-ctx")
+                .long("num-ctx")
+                .value_name("TOKENS")
+                .help("Context window size of Ollama model")
+                .value_parser(clap::value_parser!(u32))
+                .global(true),
+        )
+        .arg(
+            Arg::new("temperature")
+                .long("temperature")
+                .value_name("T")
+                .help("Sampling temperature of Ollama model")
+                .value_parser(clap::value_parser!(f32))
+                .global(true),
+        )
+        .arg(
+            Arg::new("top-p")
+                .long("top-p")
+                .value_name("P")
+                .help("Top-p sampling of Ollama model")
+                .value_parser(clap::value_parser!(f32))
+                .global(true),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("N")
+                .help("Random seed of Ollama model for reproducible answers")
+                .value_parser(clap::value_parser!(i64))
+                .global(true),
+        )
+        .arg(
+            Arg::new("keep-alive")
+                .long("keep-alive")
+                .value_name("DURATION")
+                .help("How long Ollama keeps the model loaded, e.g. 10m or -1")
+                .global(true),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Ollama answer format: json or a JSON schema")
+                .global(true),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Ask LLM for the solution as JSON instead of markdown blocks (structured output)")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stream")
+                .long("stream")
+                .help("Print LLM answer token by token while it is generated")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("retries")
+                .long("retries")
+                .value_name("N")
+                .help("Retries of a failed LLM request (rate limit, server error, connection problem)")
+                .default_value("3")
+                .value_parser(clap::value_parser!(u32))
+                .global(true),
+        )
+        .arg(
+            Arg::new("retry-budget")
+                .long("retry-budget")
+                .value_name("N")
+                .help("Maximum number of LLM request retries during the whole session")
+                .default_value("20")
+                .value_parser(clap::value_parser!(u32))
+                .global(true),
+        )
+        .arg(
+            Arg::new("retry-max-delay")
+                .long("retry-max-delay")
+                .value_name("SECONDS")
+                .help("Maximum wait between retries, longer Retry-After of the server fails the request")
+                .default_value("60")
+                .value_parser(clap::value_parser!(u64))
+                .global(true),
+        )
+        .arg(
+            Arg::new("prices")
+                .long("prices")
+                .value_name("FILE")
+                .help("JSON price table of models in dollars per million tokens for cost estimation, e.g. {\"gpt-4o-2024-08-06\": {\"input\": 2.5, \"output\": 10.0}}")
+                .global(true),
+        )
+        .arg(
+            Arg::new("budget-tokens")
+                .long("budget-tokens")
+                .value_name("N")
+                .help("Stop the session before it uses more LLM tokens")
+                .value_parser(clap::value_parser!(i64))
+                .global(true),
+        )
+        .arg(
+            Arg::new("budget-cost")
+                .long("budget-cost")
+                .value_name("DOLLARS")
+                .help("Stop the session before its estimated cost gets higher, every model needs a price in --prices")
+                .value_parser(clap::value_parser!(f64))
+                .global(true),
+        )
+        .arg(
+            Arg::new("budget-time")
+                .long("budget-time")
+                .value_name("SECONDS")
+                .help("Stop the session when it takes longer")
+                .value_parser(clap::value_parser!(u64))
+                .global(true),
+        )
+        .arg(
+            Arg::new("provider")
+                .long("provider")
+                .value_name("PROVIDER")
+                .help("LLM provider, Ollama when neither this argument nor \"provider\" in the config file is given")
+                .global(true)
+                .value_parser(["ollama", "openai", "anthropic"]),
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .value_name("DIR")
+                .help("Save LLM responses of the session to DIR for --replay")
+                .global(true),
+        )
+        .arg(
+            Arg::new("replay")
+                .long("replay")
+                .value_name("DIR")
+                .help("Answer with LLM responses saved by --record instead of calling LLM")
+                .conflicts_with("record")
+                .global(true),
+        )
+        .arg(
+            Arg::new("purge-cache")
+                .long("purge-cache")
+                .value_name("NAMESPACE")
+                .help("Remove cached results of llm, build or embedding before the start, e.g. build after a toolchain upgrade")
+                .value_parser(["llm", "build", "embedding"])
+                .action(ArgAction::Append)
+                .global(true),
+        )
+        .arg(
+            Arg::new("fallback")
+                .long("fallback")
+                .value_name("PROVIDER:MODEL")
+                .help("Model to escalate to when the previous one fails, e.g. ollama:qwen2.5-coder:7b, may be repeated")
+                .action(ArgAction::Append)
+                .global(true),
+        )
+        .arg(
+            Arg::new("escalate-after")
+                .long("escalate-after")
+                .value_name("N")
+                .help("Move to the next --fallback model after N failed rewrites in a row")
+                .default_value("2")
+                .value_parser(clap::value_parser!(u32))
+                .global(true),
+        )
+        .arg(
+            Arg::new("candidates")
+                .long("candidates")
+                .value_name("N")
+                .help("Generate N solutions in parallel with different temperatures and seeds, keep the first passing one")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .global(true),
+        )
+        .arg(
+            Arg::new("emb-batch-size")
+                .long("emb-batch-size")
+                .value_name("N")
+                .help("Files per embedding request of 'ask' command")
+                .default_value("32")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .global(true),
+        )
+        .arg(
+            Arg::new("emb-concurrency")
+                .long("emb-concurrency")
+                .value_name("N")
+                .help("Embedding requests sent at the same time")
+                .default_value("4")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .global(true),
+        )
+        .arg(
+            Arg::new("key-command")
+                .long("key-command")
+                .value_name("COMMAND")
+                .help("Command printing the API key, e.g. \"pass show openai\"")
+                .global(true),
+        )
+        .arg(
+            Arg::new("openai-url")
+                .long("openai-url")
+                .value_name("URL")
+                .help("Use an OpenAI-compatible API (llama.cpp server, vLLM, LM Studio...), e.g. http://127.0.0.1:8080/v1")
+                .global(true),
+        )
+        .arg(
+            Arg::new("openai-model")
+                .long("openai-model")
+                .value_name("MODEL")
+                .help("Set desired model of OpenAI-compatible API")
+                .default_value("gpt-4o-2024-08-06")
+                .global(true),
+        )
+        .arg(
+            Arg::new("openai-emb")
+                .long("openai-emb")
+                .value_name("EMBEDDING-MODEL")
+                .help("Set desired embedding model of OpenAI-compatible API")
+                .default_value("text-embedding-ada-002")
+                .global(true),
+        )
+        .arg(
+            Arg::new("openai-key")
+                .long("openai-key")
+                .value_name("API-KEY")
+                .help("API key of OpenAI-compatible API (optional for local servers)")
+                .global(true),
+        )
+        .arg(
+            Arg::new("anthropic-key")
+                .long("anthropic-key")
+                .value_name("API-KEY")
+                .help("Use Anthropic Messages API with this API key")
+                .global(true),
+        )
+        .arg(
+            Arg::new("anthropic-model")
+                .long("anthropic-model")
+                .value_name("MODEL")
+                .help("Set desired Anthropic model")
+                .default_value("claude-3-5-sonnet-20241022")
+                .global(true),
+        )
+        .arg(
+            Arg::new("anthropic-url")
+                .long("anthropic-url")
+                .value_name("URL")
+                .help("Base URL of Anthropic Messages API")
+                .default_value(llm_anthropic::ANTHROPIC_URL)
+                .global(true),
+        )
         .subcommand(
             Command::new("generate")
                 .about("Generate code")
@@ -103,11 +398,59 @@ Usage:
                         .index(1),
                 ),
         )
+        .subcommand(
+            Command::new("cache")
+                .about("Inspect and clean the cache of LLM answers, build results and embeddings")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("stats").about("Entries and bytes of every namespace"),
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("Entries, the most recently used first")
+                        .arg(Arg::new("namespace")
+                                .long("namespace")
+                                .value_name("NAMESPACE")
+                                .value_parser(["llm", "build", "embedding"])
+                                .help("Only entries of this namespace")),
+                )
+                .subcommand(
+                    Command::new("show").about("Entry and its value").arg(
+                        Arg::new("key")
+                            .help("Key or its beginning, as printed by list")
+                            .required(true)
+                            .index(1),
+                    ),
+                )
+                .subcommand(
+                    Command::new("purge")
+                        .about("Remove entries, all of them without arguments")
+                        .arg(Arg::new("namespace")
+                                .long("namespace")
+                                .value_name("NAMESPACE")
+                                .value_parser(["llm", "build", "embedding"])
+                                .help("Only entries of this namespace"))
+                        .arg(
+                            Arg::new("older-than")
+                                .long("older-than")
+                                .value_name("DURATION")
+                                .help("Only entries created before, like 30d, 12h or 15m"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("verify").about("Check cache.bin for damaged records and values"),
+                ),
+        )
         .get_matches();
 
     let verbose = matches.get_one::<bool>("verbose").unwrap();
     *VERBOSE.lock().unwrap() = *verbose;
 
+    if let Some(matches) = matches.subcommand_matches("cache") {
+        handle_cache_command(matches);
+        return;
+    }
+
     let lang: Lang = matches
         .get_one::<String>("lang")
         .unwrap()
@@ -135,7 +478,20 @@ Usage:
         }
     }
 
-    let mut cache = cache::Cache::new();
+    let record = matches.get_one::<String>("record");
+    let replay = matches.get_one::<String>("replay");
+    // Fixtures are numbered in the order of requests, which must not depend on thread timing
+    let mut candidates = *matches.get_one::<u32>("candidates").unwrap();
+    if candidates > 1 && (record.is_some() || replay.is_some()) {
+        println!("--candidates is ignored with --record and --replay");
+        candidates = 1;
+    }
+    // Tokens of parallel candidates would be mixed in one output
+    let mut stream = *matches.get_one::<bool>("stream").unwrap();
+    if stream && candidates > 1 {
+        println!("--stream is ignored with several --candidates");
+        stream = false;
+    }
 
     let prompt_file_path = format!("prompt/{}.txt", lang);
     if !std::path::Path::new(&prompt_file_path).exists() {
@@ -174,41 +530,159 @@ Usage:
     }
 
     let prompt = llm_prompt::Prompt::new(format!("prompt/{}.txt", lang).as_str());
-    // if file token.txt exists
-    let llm = if std::path::Path::new("token.txt").exists() {
-        println!("Use OpenAI API");
-        println!("");
-        let token = std::fs::read_to_string("token.txt").unwrap();
-        llm_api::LLMApi::new(llm_api::ModelType::OpenAI {
-            api_key: token.trim().to_string(),
-        })
+    let mut config = config::Config::load(matches.get_one::<String>("config").map(|s| s.as_str()))
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+    config.ollama.override_with(&matches);
+    let limits = config.cache.limits().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    // A recorded session must contain every LLM call, and a replayed one must not leave traces
+    let cache = if record.is_some() || replay.is_some() {
+        cache::Cache::in_memory()
     } else {
-        let ollama_model: String = matches
-            .get_one::<String>("ollmod")
-            .unwrap()
-            .parse()
-            .unwrap_or_else(|err| {
-                eprintln!("{}", err);
-                std::process::exit(1);
-            });
-        println!("Warning: Cant find \"token.txt\" file for OpenAI API integration.");
-        println!("Use Ollama API: {}", OLLAMA_API);
-        println!("Use Ollama model: {}", ollama_model);
-        println!("");
-
-        let emb: String = matches
-            .get_one::<String>("ollemb")
-            .unwrap()
-            .parse()
-            .unwrap_or_else(|err| {
+        cache::Cache::new().with_limits(limits)
+    };
+    if let Some(namespaces) = matches.get_many::<String>("purge-cache") {
+        for namespace in namespaces {
+            let namespace = cache::Namespace::parse(namespace).unwrap();
+            let removed = cache.purge(Some(namespace), None);
+            println!("Removed {} entries of {} cache", removed, namespace);
+        }
+    }
+    // A key file or an URL in the working folder never switches the provider silently
+    let (provider, reason) = match matches.get_one::<String>("provider") {
+        _ if replay.is_some() => ("replay".to_string(), "--replay argument"),
+        Some(provider) => (provider.to_string(), "--provider argument"),
+        None => match &config.provider {
+            Some(provider) => (provider.to_string(), "config file"),
+            None => ("ollama".to_string(), "default"),
+        },
+    };
+    println!("Use provider: {} (by {})", provider, reason);
+    if reason == "default" {
+        if matches.get_one::<String>("anthropic-key").is_some() {
+            println!("--anthropic-key is not used, add --provider=anthropic to use Anthropic");
+        }
+        let openai_source = if matches.get_one::<String>("openai-url").is_some() {
+            Some("--openai-url")
+        } else if std::path::Path::new("token.txt").exists() {
+            Some("token.txt")
+        } else {
+            None
+        };
+        if let Some(source) = openai_source {
+            println!(
+                "{} is not used, add --provider=openai to use OpenAI API",
+                source
+            );
+        }
+    }
+    let fallback = match matches.get_many::<String>("fallback") {
+        Some(models) => models.cloned().collect(),
+        None => config.fallback.clone(),
+    };
+    let fallback = fallback
+        .iter()
+        .map(|spec| {
+            config::split_model(spec).unwrap_or_else(|err| {
                 eprintln!("{}", err);
                 std::process::exit(1);
-            });
-        llm_api::LLMApi::new(llm_api::ModelType::Ollama {
-            model: ollama_model,
-            emb,
+            })
         })
+        .collect::<Vec<_>>();
+    let mut backends: Vec<Box<dyn llm_api::LlmBackend>> = match replay {
+        Some(dir) => {
+            println!("Replay LLM responses from {}", dir);
+            println!();
+            // The recorded session may have escalated along the chain
+            let replay = llm_replay::ReplayBackend::new(std::path::Path::new(dir));
+            let mut backends: Vec<Box<dyn llm_api::LlmBackend>> = vec![];
+            for number in 1..=fallback.len() {
+                backends.push(Box::new(replay.for_fallback(number)));
+            }
+            backends.insert(0, Box::new(replay));
+            backends
+        }
+        None => {
+            let mut backends = vec![create_backend(&provider, None, stream, &matches, &config)];
+            for (number, (provider, model)) in fallback.iter().enumerate() {
+                println!("Fallback model {}: {}:{}", number + 1, provider, model);
+                backends.push(create_backend(
+                    provider,
+                    Some(model),
+                    stream,
+                    &matches,
+                    &config,
+                ));
+            }
+            backends
+        }
     };
+    if let Some(dir) = record {
+        println!("Record LLM responses to {}", dir);
+        let recorder =
+            llm_replay::RecordBackend::new(std::path::Path::new(dir), backends.remove(0));
+        let mut recorded: Vec<Box<dyn llm_api::LlmBackend>> = vec![];
+        for backend in backends {
+            recorded.push(Box::new(recorder.for_fallback(backend)));
+        }
+        recorded.insert(0, Box::new(recorder));
+        backends = recorded;
+    }
+    let emb_concurrency = match record.or(replay) {
+        Some(_) => 1,
+        None => *matches.get_one::<u32>("emb-concurrency").unwrap() as usize,
+    };
+    let mut backends = backends.into_iter();
+    let mut llm = llm_api::LLMApi::new(backends.next().unwrap()).with_emb_batch(
+        *matches.get_one::<u32>("emb-batch-size").unwrap() as usize,
+        emb_concurrency,
+    );
+    for backend in backends {
+        llm = llm.with_fallback(backend);
+    }
+    let prices = match matches.get_one::<String>("prices") {
+        Some(file_name) => usage::UsageTracker::load_prices(file_name).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }),
+        None => HashMap::new(),
+    };
+    let usage = usage::UsageTracker::new(prices);
+    // Without a price every call would look free and the budget would never stop a session
+    if matches.get_one::<f64>("budget-cost").is_some() {
+        let mut models = llm.models();
+        // Only `ask` embeds, its embedding calls count against the budget as well
+        if matches.subcommand_name() == Some("ask") {
+            models.push(llm.emb_model());
+        }
+        let unpriced = usage.unpriced(&models);
+        if !unpriced.is_empty() {
+            eprintln!(
+                "--budget-cost needs a price of every model in --prices, missing: {}",
+                unpriced.join(", ")
+            );
+            std::process::exit(1);
+        }
+    }
+    let llm = llm
+        .with_usage(usage)
+        .with_retry(llm_retry::RetryPolicy::new(
+            *matches.get_one::<u32>("retries").unwrap(),
+            *matches.get_one::<u32>("retry-budget").unwrap(),
+            std::time::Duration::from_secs(*matches.get_one::<u64>("retry-max-delay").unwrap()),
+        ))
+        .with_budget(usage::Budget::new(
+            matches.get_one::<i64>("budget-tokens").copied(),
+            matches.get_one::<f64>("budget-cost").copied(),
+            matches
+                .get_one::<u64>("budget-time")
+                .map(|secs| std::time::Duration::from_secs(*secs)),
+        ));
 
     println!(
         "Use '\\' char in the end of line for multiline mode or just copy-paste multiline text."
@@ -224,7 +698,13 @@ Usage:
             println!("Explain what the function should do:");
             let question: String = ask();
 
-            state_machine::run_state_machine(&lang, &question, &prompt, &mut cache, &llm);
+            let options = state_machine::Options {
+                json_output: *matches.get_one::<bool>("json").unwrap(),
+                escalate_after: *matches.get_one::<u32>("escalate-after").unwrap(),
+                candidates,
+                ..state_machine::Options::default()
+            };
+            state_machine::run_state_machine(&lang, &question, &prompt, &cache, &llm, &options);
             println!("++++++++ Finished ++++++++++++");
         }
         Some("ask") => {
@@ -234,50 +714,50 @@ Usage:
                 .get_one("path")
                 .unwrap();
             println!("Path: {:?}", path);
-            match lang {
-                Lang::Rust => {
-                    handle_ask_command(
-                        path,
-                        &lang,
-                        &llm,
-                        &mut cache,
-                        &prompt,
-                        vec![String::from("rs"), String::from("toml")],
-                        vec![String::from("target")],
-                        "Explain how this code works and what it do:",
-                        "Use functions from code above to give answer for this question:",
-                    );
-                }
-                Lang::CSharp => {
-                    handle_ask_command(
-                        path,
-                        &lang,
-                        &llm,
-                        &mut cache,
-                        &prompt,
-                        vec![String::from("cs")],
-                        vec![String::from("bin"), String::from("obj")],
-                        "Explain how this code works and what it does:",
-                        "Use the code above to answer the following question:",
-                    );
-                }
-                Lang::JavaScript => {
-                    handle_ask_command(
-                        path,
-                        &lang,
-                        &llm,
-                        &mut cache,
-                        &prompt,
-                        vec![String::from("js")],
-                        vec![String::from("node_modules")],
-                        "Explain how this JavaScript code works and what it does:",
-                        "Use the code above to answer the following question:",
-                    );
-                }
+            let result = match lang {
+                Lang::Rust => handle_ask_command(
+                    path,
+                    &lang,
+                    &llm,
+                    &cache,
+                    &prompt,
+                    vec![String::from("rs"), String::from("toml")],
+                    vec![String::from("target")],
+                    "Explain how this code works and what it do:",
+                    "Use functions from code above to give answer for this question:",
+                ),
+                Lang::CSharp => handle_ask_command(
+                    path,
+                    &lang,
+                    &llm,
+                    &cache,
+                    &prompt,
+                    vec![String::from("cs")],
+                    vec![String::from("bin"), String::from("obj")],
+                    "Explain how this code works and what it does:",
+                    "Use the code above to answer the following question:",
+                ),
+                Lang::JavaScript => handle_ask_command(
+                    path,
+                    &lang,
+                    &llm,
+                    &cache,
+                    &prompt,
+                    vec![String::from("js")],
+                    vec![String::from("node_modules")],
+                    "Explain how this JavaScript code works and what it does:",
+                    "Use the code above to answer the following question:",
+                ),
                 _ => {
                     println!("Unsupported language: {:?}", lang);
                     std::process::exit(1);
                 }
+            };
+            if let Err(err) = result {
+                state_machine::print_error("Stopped", &err);
+                println!("++++++++ Usage ++++++++++++");
+                print!("{}", llm.usage().summary());
+                std::process::exit(1);
             }
 
             println!("++++++++ Finished ++++++++++++");
@@ -287,66 +767,348 @@ Usage:
             std::process::exit(1);
         }
     }
+
+    println!("++++++++ Usage ++++++++++++");
+    print!("{}", llm.usage().summary());
+}
+
+// Client of `provider`, `model` None selects the main model of the command line arguments
+fn create_backend(
+    provider: &str,
+    model: Option<&str>,
+    stream: bool,
+    matches: &clap::ArgMatches,
+    config: &config::Config,
+) -> Box<dyn llm_api::LlmBackend> {
+    let openai_url = matches.get_one::<String>("openai-url");
+    let openai_model = model.unwrap_or(matches.get_one::<String>("openai-model").unwrap());
+    let openai_emb = matches.get_one::<String>("openai-emb").unwrap();
+    let openai_key = matches.get_one::<String>("openai-key").map(|s| s.as_str());
+    let anthropic_key = matches
+        .get_one::<String>("anthropic-key")
+        .map(|s| s.as_str());
+    let key_command = matches.get_one::<String>("key-command").map(|s| s.as_str());
+    let find_api_key = |provider: &str, cli_key: Option<&str>| {
+        credentials::find_api_key(provider, cli_key, key_command).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    };
+    match provider {
+        "anthropic" => {
+            let url = matches.get_one::<String>("anthropic-url").unwrap();
+            let model = model.unwrap_or(matches.get_one::<String>("anthropic-model").unwrap());
+            let api_key = find_api_key("anthropic", anthropic_key).unwrap_or_else(|| {
+                eprintln!("Anthropic API key is not found. Set ANTHROPIC_API_KEY environment variable, use --anthropic-key or --key-command, or add it to {}", credentials::credentials_file().map_or(String::new(), |f| f.display().to_string()));
+                std::process::exit(1);
+            });
+            println!("Use Anthropic API: {}", url);
+            println!("Use Anthropic model: {}", model);
+            println!("Use API key {} from {}", api_key.masked(), api_key.source);
+            println!();
+            Box::new(llm_anthropic::AnthropicBackend::new(
+                url,
+                model.to_string(),
+                api_key.key,
+            ))
+        }
+        "openai" => {
+            let url = openai_url.map_or(llm_openai::OPENAI_URL, |url| url.as_str());
+            let api_key = find_api_key("openai", openai_key);
+            // Local OpenAI-compatible servers usually need no key
+            if api_key.is_none() && url == llm_openai::OPENAI_URL {
+                eprintln!("OpenAI API key is not found. Set OPENAI_API_KEY environment variable, use --openai-key or --key-command, or add it to {}", credentials::credentials_file().map_or(String::new(), |f| f.display().to_string()));
+                std::process::exit(1);
+            }
+            println!("Use OpenAI API: {}", url);
+            println!("Use OpenAI model: {}", openai_model);
+            println!("Use OpenAI embedding model: {}", openai_emb);
+            match &api_key {
+                Some(api_key) => {
+                    println!("Use API key {} from {}", api_key.masked(), api_key.source)
+                }
+                None => println!("Use no API key"),
+            }
+            println!();
+            Box::new(llm_openai::OpenAIBackend::new(
+                url,
+                openai_model.to_string(),
+                openai_emb.to_string(),
+                api_key.map(|api_key| api_key.key),
+                stream,
+            ))
+        }
+        "ollama" => {
+            let ollama_model = model
+                .unwrap_or(matches.get_one::<String>("ollmod").unwrap())
+                .to_string();
+            let url = config
+                .ollama
+                .url
+                .clone()
+                .unwrap_or_else(|| llm_ollama::OLLAMA_URL.to_string());
+            let emb = matches.get_one::<String>("ollemb").unwrap().to_string();
+            println!("Use Ollama API: {}", url);
+            println!("Use Ollama model: {}", ollama_model);
+            println!("Use Ollama embedding model: {}", emb);
+            println!();
+
+            let backend = llm_ollama::OllamaBackend::new(
+                &url,
+                ollama_model.clone(),
+                emb.clone(),
+                stream,
+                config.ollama.clone(),
+            );
+            if let Err(err) = backend.check_server() {
+                eprintln!("Ollama server is not available at {}: {}", url, err);
+                eprintln!("Start it with \"ollama serve\" or point to another server with --ollama-url or OLLAMA_HOST.");
+                std::process::exit(1);
+            }
+            let mut models = vec![ollama_model.as_str()];
+            // Embeddings always come from the first model of the chain
+            if model.is_none() && matches.subcommand_name() == Some("ask") {
+                models.push(emb.as_str());
+            }
+            let missing = backend.missing_models(&models).unwrap_or_else(|err| {
+                eprintln!("Can't get the list of Ollama models: {}", err);
+                std::process::exit(1);
+            });
+            for model in missing {
+                if !*matches.get_one::<bool>("pull").unwrap() {
+                    eprintln!("Model \"{}\" is not pulled to Ollama server.", model);
+                    eprintln!(
+                        "Pull it with \"ollama pull {}\" or run rustsn with --pull.",
+                        model
+                    );
+                    std::process::exit(1);
+                }
+                println!("Pull model \"{}\"", model);
+                if let Err(err) = backend.pull(&model) {
+                    eprintln!("Can't pull model \"{}\": {}", model, err);
+                    std::process::exit(1);
+                }
+            }
+            Box::new(backend)
+        }
+        other => {
+            eprintln!(
+                "Unknown provider \"{}\", use ollama, openai or anthropic",
+                other
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn handle_cache_command(matches: &clap::ArgMatches) {
+    let namespace = |matches: &clap::ArgMatches| {
+        matches
+            .get_one::<String>("namespace")
+            .and_then(|namespace| cache::Namespace::parse(namespace))
+    };
+    // Opening the cache repairs it, so the file is checked before
+    if matches.subcommand_name() == Some("verify") {
+        match cache::verify(cache::CACHE_FILE) {
+            Ok(verification) if verification.outdated => println!(
+                "{} has an outdated format, it is moved to {}.old at the next start",
+                cache::CACHE_FILE,
+                cache::CACHE_FILE
+            ),
+            Ok(verification) => {
+                println!(
+                    "{}: {} records, {} entries",
+                    cache::CACHE_FILE,
+                    verification.records,
+                    verification.entries
+                );
+                if verification.damaged_bytes > 0 {
+                    println!(
+                        "Damaged after the last record, {} bytes are dropped at the next start",
+                        verification.damaged_bytes
+                    );
+                }
+                if verification.invalid_values > 0 {
+                    println!(
+                        "{} entries have invalid values, they are never used",
+                        verification.invalid_values
+                    );
+                }
+                if verification.damaged_bytes == 0 && verification.invalid_values == 0 {
+                    println!("No problems found");
+                }
+            }
+            Err(err) => {
+                eprintln!("Can't read {}: {}", cache::CACHE_FILE, err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let cache = cache::Cache::new();
+    match matches.subcommand() {
+        Some(("stats", _)) => {
+            println!("{:<10} {:>10} {:>14}", "Namespace", "Entries", "Bytes");
+            let stats = cache.stats();
+            for stats in &stats {
+                println!(
+                    "{:<10} {:>10} {:>14}",
+                    stats.namespace, stats.entries, stats.size
+                );
+            }
+            println!(
+                "{:<10} {:>10} {:>14}",
+                "Total",
+                stats.iter().map(|stats| stats.entries).sum::<usize>(),
+                stats.iter().map(|stats| stats.size).sum::<u64>()
+            );
+        }
+        Some(("list", matches)) => {
+            let now = unix_time();
+            for entry in cache.list(namespace(matches)) {
+                println!(
+                    "{:<10} {} {:>10} {:>8} {}",
+                    entry.namespace,
+                    entry.key,
+                    entry.size,
+                    age(now, entry.used),
+                    entry.preview
+                );
+            }
+        }
+        Some(("show", matches)) => {
+            let key = matches.get_one::<String>("key").unwrap();
+            let (entry, value) = cache.show(key).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+            let now = unix_time();
+            println!("Namespace: {}", entry.namespace);
+            println!("Key: {}", entry.key);
+            println!("Size: {} bytes", entry.size);
+            println!("Created: {} ago", age(now, entry.created));
+            println!("Used: {} ago", age(now, entry.used));
+            // LLM answers are shown as text, other values as JSON
+            match serde_json::from_str::<serde_json::Value>(&value) {
+                Ok(serde_json::Value::String(text)) => println!("{}", text),
+                _ => println!("{}", value),
+            }
+        }
+        Some(("purge", matches)) => {
+            let older_than = matches.get_one::<String>("older-than").map(|older_than| {
+                config::parse_duration(older_than).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                })
+            });
+            let removed = cache.purge(namespace(matches), older_than);
+            println!("Removed {} entries", removed);
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// "45s", "12m", "3h" or "2d"
+fn age(now: u64, time: u64) -> String {
+    let seconds = now.saturating_sub(time);
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m", seconds / 60),
+        3600..86400 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
 }
 
 fn handle_ask_command(
     path: &String,
     _lang: &Lang,
     llm: &llm_api::LLMApi,
-    cache: &mut cache::Cache,
+    cache: &cache::Cache,
     prompt: &llm_prompt::Prompt,
     extensions: Vec<String>,
     exclude_dirs: Vec<String>,
     _explain_prompt: &str,
     answer_prompt: &str,
-) {
+) -> Result<(), llm_api::LlmError> {
     let files = file_explorer::explore_files(&path, &extensions, &exclude_dirs);
-    let mut vectors: HashMap<String, Vec<f32>> = HashMap::new();
+    let mut contents = vec![];
     for file in &files {
-        println!("File: {:?}", file);
+        if *VERBOSE.lock().unwrap() {
+            println!("File: {:?}", file);
+        }
         let content_file = std::fs::read_to_string(file).unwrap();
-        let content = format!("# {}\r\n{}", file, content_file);
+        contents.push(format!("# {}\r\n{}", file, content_file));
 
         // let prompt_template = format!("{}\r\n{}", content, _explain_prompt);
         // let llm_code_explanation = llm.request(&prompt_template, &Vec::new(), cache, prompt);
         // let full_content = format!("{}\r\n{}", content, llm_code_explanation);
         // let emb = llm.emb(&content, cache, &full_content);
-        let emb = llm.emb(&content, cache, &content);
-        vectors.insert(file.clone(), emb);
     }
+    let progress = |ready, all| {
+        print!("\rIndexed {}/{} files", ready, all);
+        let _ = std::io::Write::flush(&mut std::io::stdout());
+        if ready == all {
+            println!();
+        }
+    };
+    let embeddings = llm.emb_batch(&contents, cache, &progress)?;
+    let mut vectors: HashMap<String, Vec<f32>> = files.iter().cloned().zip(embeddings).collect();
 
     println!("Enter the question about your project sources:");
     let question: String = ask();
-    let target_emb = llm.emb(&question, cache, &question);
+    let target_emb = llm.emb(&question, cache, &question)?;
+    // The embedding model changed while the question was typed
+    if vectors
+        .values()
+        .any(|vector| vector.len() != target_emb.len())
+    {
+        let embeddings = llm.emb_batch(&contents, cache, &progress)?;
+        vectors = files.iter().cloned().zip(embeddings).collect();
+    }
     let result = vector_utils::find_closest(&target_emb, &vectors);
     let limited_result = result.iter().take(3).collect::<Vec<_>>();
     println!("Find closest files:");
     for (k, _v) in &limited_result {
         println!("File: {}", k);
     }
+    // The closest files share what is left of the window after the question and the answer
+    let estimator = llm.estimator(0);
+    let file_tokens = llm.context_window(0).map(|window| {
+        let reserved = estimator.tokens(answer_prompt)
+            + estimator.tokens(&question)
+            + llm_api::MAX_TOKENS as usize;
+        window.saturating_sub(reserved) / limited_result.len().max(1)
+    });
     let files_content_vec = limited_result
         .iter()
         .map(|(k, _)| {
             let content = std::fs::read_to_string(k).unwrap();
+            let content = match file_tokens {
+                Some(file_tokens) => estimator.truncate(&content, file_tokens),
+                None => content,
+            };
             format!("# {} \r\n{}", k, content)
         })
         .collect::<Vec<_>>();
     let files_content = files_content_vec.join("\r\n");
 
-    let prompt_template = format!(
-        "{}\r\n{}\r\n{}",
-        files_content,
-        answer_prompt,
-        question
-    );
+    let prompt_template = format!("{}\r\n{}\r\n{}", files_content, answer_prompt, question);
     if *VERBOSE.lock().unwrap() {
         println!("Request: {}", prompt_template);
     }
-    let answer = llm.request(&prompt_template, &Vec::new(), cache, prompt);
+    let answer = llm.request(&prompt_template, &Vec::new(), cache, prompt)?;
 
     println!("++++++++ Answer ++++++++++++");
 
     println!("Answer: {}", answer);
+    Ok(())
 }
 
 fn ask() -> String {
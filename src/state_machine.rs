@@ -4,528 +4,475 @@ use crate::build_tool::{
     create_project_swift, create_project_typescript,
 };
 use crate::cache::Cache;
-use crate::llm_api::LLMApi;
+use crate::conversation::Conversation;
+use crate::llm_api::{ChatMessage, LLMApi, LlmError, Sampling, MAX_TOKENS};
 use crate::llm_prompt::Prompt;
-use crate::llm_response::LLMResponse;
-use crate::{Lang, MAX_NUMBER_OF_ATTEMPTS, VERBOSE};
+use crate::llm_response::{LLMResponse, Project};
+use crate::{Lang, MAX_HISTORY_TOKENS, MAX_NUMBER_OF_ATTEMPTS, VERBOSE};
+use std::sync::atomic::{AtomicU32, Ordering};
 
-pub fn run_state_machine(
-    lang: &Lang,
-    question: &str,
-    prompt: &Prompt,
-    cache: &mut Cache,
-    llm: &LLMApi,
-) {
-    match lang {
-        Lang::Rust => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Rust);
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-            create_project_rust(lang, &project);
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_code,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Rust);
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-                    create_project_rust(lang, &project);
-                    build_res = build_tool(lang, &project.build_command, cache);
-                    test_res = build_tool(lang, &project.test_command, cache);
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
-        }
-        Lang::Java => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Java);
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-            create_project_java(&project);
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Java);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_java(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
+// Limit of the build or the test output sent back to the model
+const MAX_OUTPUT_TOKENS: usize = 2000;
 
-                    test_res = build_tool(lang, &project.test_command, cache);
+// Project with the most progress: built and tested, then built, then anything
+struct Best {
+    project: Project,
+    score: u8,
+}
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+impl Best {
+    fn update(best: &mut Option<Best>, project: &Project, build_ok: bool, test_ok: bool) {
+        let score = build_ok as u8 + (build_ok && test_ok) as u8;
+        if best.as_ref().is_none_or(|best| score > best.score) {
+            *best = Some(Best {
+                project: project.clone(),
+                score,
+            });
         }
-        Lang::Scala => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Scala);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_scala(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Scala);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_scala(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
-
-                    test_res = build_tool(lang, &project.test_command, cache);
+    }
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+    fn state(&self) -> &'static str {
+        match self.score {
+            2 => "build and tests passed",
+            1 => "build passed, tests failed",
+            _ => "build failed",
         }
-        Lang::Swift => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Swift);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_swift(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Swift);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_swift(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
+    }
+}
 
-                    test_res = build_tool(lang, &project.test_command, cache);
+/// Settings of a code generation session.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Ask for the answer as JSON instead of markdown.
+    pub json_output: bool,
+    /// Move to the next model of the fallback chain after so many failed rewrites in a row.
+    pub escalate_after: u32,
+    /// Solutions generated in parallel, each one built and tested in its own folder.
+    pub candidates: u32,
+    /// Folder of the resulting project, candidates use "<sandbox>_1", "<sandbox>_2"...
+    pub sandbox: String,
+}
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            json_output: false,
+            escalate_after: 2,
+            candidates: 1,
+            sandbox: "sandbox".to_string(),
         }
-        Lang::Kotlin => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Kotlin);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_kotlin(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Kotlin);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_kotlin(&project);
+    }
+}
 
-                    build_res = build_tool(lang, &project.build_command, cache);
+// Everything the candidates of a session share
+struct Session<'a> {
+    lang: &'a Lang,
+    question: &'a str,
+    prompt: &'a Prompt,
+    cache: &'a Cache,
+    llm: &'a LLMApi,
+    options: &'a Options,
+    // Number of the first candidate which passed the tests, the others stop then
+    winner: AtomicU32,
+}
 
-                    test_res = build_tool(lang, &project.test_command, cache);
+// One of the parallel solutions, with its own folder and sampling settings
+struct Candidate {
+    number: u32,
+    sandbox: String,
+    sampling: Sampling,
+}
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+impl Candidate {
+    // The first candidate keeps the settings of the backend, the others get own seeds and
+    // higher temperatures, so that they don't all repeat the same mistake
+    fn new(options: &Options, number: u32) -> Candidate {
+        let sampling = match number {
+            1 => Sampling::default(),
+            _ => Sampling {
+                temperature: Some((0.5 + 0.1 * number as f32).min(1.0)),
+                seed: Some(number as i64),
+            },
+        };
+        Candidate {
+            number,
+            sandbox: format!("{}_{}", options.sandbox, number),
+            sampling,
         }
-        Lang::Python => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Python);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_python(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Python);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_python(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
-
-                    test_res = build_tool(lang, &project.test_command, cache);
+    }
+}
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
+pub fn run_state_machine(
+    lang: &Lang,
+    question: &str,
+    prompt: &Prompt,
+    cache: &Cache,
+    llm: &LLMApi,
+    options: &Options,
+) {
+    // Rejected before the first request, create_project would fail only after it is paid for
+    if !supported(lang) {
+        panic!("Unknown lang: {}", lang);
+    }
+    let session = Session {
+        lang,
+        question,
+        prompt,
+        cache,
+        llm,
+        options,
+        winner: AtomicU32::new(0),
+    };
+    if options.candidates <= 1 {
+        let candidate = Candidate {
+            number: 1,
+            sandbox: options.sandbox.clone(),
+            sampling: Sampling::default(),
+        };
+        let mut best = None;
+        if let Err(err) = generate(&session, &candidate, &mut best) {
+            print_error("Stopped", &err);
+            // The sandbox may hold a later, worse attempt
+            if let Some(best) = best {
+                println!(
+                    "Best result so far ({}) is saved in {} folder",
+                    best.state(),
+                    options.sandbox
+                );
+                create_project(lang, &options.sandbox, &best.project);
             }
         }
-        Lang::JavaScript => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::JavaScript);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            if *VERBOSE.lock().unwrap() {
-                create_project_javascript(&project);
-            }
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::JavaScript);
-
-                    println!("{:#?}", project);
-
-                    create_project_javascript(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
-
-                    test_res = build_tool(lang, &project.test_command, cache);
+        return;
+    }
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+    let candidates = (1..=options.candidates)
+        .map(|number| Candidate::new(options, number))
+        .collect::<Vec<_>>();
+    println!("Generate {} candidates in parallel", candidates.len());
+    let results = std::thread::scope(|scope| {
+        let handles = candidates
+            .iter()
+            .map(|candidate| {
+                let session = &session;
+                scope.spawn(move || {
+                    let mut best = None;
+                    let result = generate(session, candidate, &mut best);
+                    (result, best)
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    // The first passing candidate wins, without one the most advanced
+    let winner = session.winner.load(Ordering::SeqCst);
+    let mut best: Option<Best> = None;
+    for (candidate, (result, candidate_best)) in candidates.iter().zip(results) {
+        if let Err(err) = result {
+            print_error(&format!("Candidate {} stopped", candidate.number), &err);
         }
-        Lang::TypeScript => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::TypeScript);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
+        if let Some(candidate_best) = candidate_best {
+            if candidate.number == winner
+                || (winner == 0 && best.as_ref().is_none_or(|b| candidate_best.score > b.score))
+            {
+                best = Some(candidate_best);
             }
+        }
+        let _ = std::fs::remove_dir_all(&candidate.sandbox);
+    }
+    if let Some(best) = best {
+        println!(
+            "Best of {} candidates ({}) is saved in {} folder",
+            candidates.len(),
+            best.state(),
+            options.sandbox
+        );
+        create_project(lang, &options.sandbox, &best.project);
+    }
+}
 
-            create_project_typescript(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    let ts_config = project.additional_config[0].clone();
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
+pub(crate) fn print_error(stopped: &str, err: &LlmError) {
+    match err {
+        LlmError::BudgetExceeded(_) => println!("{} because {}", stopped, err),
+        _ => println!("{} because of LLM error: {}", stopped, err),
+    }
+}
 
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            ts_config,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::TypeScript);
+fn generate(
+    session: &Session,
+    candidate: &Candidate,
+    best: &mut Option<Best>,
+) -> Result<(), LlmError> {
+    let (lang, prompt, cache) = (session.lang, session.prompt, session.cache);
+    let sandbox = candidate.sandbox.as_str();
+    let json_output = session.options.json_output;
+    // In structured output mode every prompt ends with the JSON instruction
+    let schema = json_output.then(|| Project::json_schema(lang));
+    let with_json = |text: String| match json_output {
+        true => format!("{}\n\n{}", text, Project::json_instruction(lang)),
+        false => text,
+    };
+
+    // Prompts with a "feedback" section fix the code in one conversation, older ones
+    // send the last attempt in a single "rewrite" prompt
+    let multi_turn = prompt.contains("feedback");
+    let system = if prompt.contains("system") {
+        Some(prompt.create("system", &vec![]))
+    } else {
+        None
+    };
+    let task = with_json(prompt.create("generate", &vec![session.question.to_string()]));
+    // Position in the fallback chain, every candidate escalates on its own
+    let mut model = 0;
+    let mut conversation = Conversation::new(
+        system,
+        &task,
+        history_tokens(session.llm, model),
+        session.llm.estimator(model),
+    );
+
+    let (mut result, mut project) = ask(
+        session,
+        candidate,
+        &mut model,
+        "generate",
+        &mut |model| match multi_turn {
+            true => conversation_messages(session.llm, &mut conversation, model),
+            false => vec![ChatMessage::user(&task)],
+        },
+        &schema,
+    )?;
+    if *VERBOSE.lock().unwrap() {
+        println!("{:#?}", project);
+    }
+    create_project(lang, sandbox, &project);
+    let mut build_res = build_tool(lang, sandbox, &project.build_command, cache);
+    let mut test_res = build_tool(lang, sandbox, &project.test_command, cache);
+    Best::update(best, &project, build_res.0, test_res.0);
+
+    let mut number_of_attempts = 0;
+    let mut failed_rewrites = 0;
+    while !(build_res.0 && test_res.0) {
+        if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
+            println!("To many attempts");
+            break;
+        }
+        if session.winner.load(Ordering::SeqCst) != 0 {
+            println!("Candidate {} stops, another one passed", candidate.number);
+            break;
+        }
+        number_of_attempts += 1;
+        // A model which can't fix the code gives its place to the next, more capable one
+        if failed_rewrites >= session.options.escalate_after
+            && escalate(session, candidate, &mut model)
+        {
+            failed_rewrites = 0;
+            number_of_attempts = 1;
+        }
+        // Thousands of lines of compiler errors would push the code out of a small window
+        let estimator = session.llm.estimator(model);
+        let max_output = output_tokens(session.llm.context_window(model));
+        build_res.1 = estimator.trim_middle(&build_res.1, max_output);
+        test_res.1 = estimator.trim_middle(&test_res.1, max_output);
+        let rewrite = if multi_turn {
+            let feedback = vec![
+                project.build_command.clone(),
+                build_res.1,
+                project.test_command.clone(),
+                test_res.1,
+            ];
+            conversation.push_assistant(&result);
+            conversation.push_user(&with_json(prompt.create("feedback", &feedback)));
+            None
+        } else {
+            let params = rewrite_params(lang, project, build_res.1, test_res.1, session.question);
+            Some(ChatMessage::user(&with_json(
+                prompt.create("rewrite", &params),
+            )))
+        };
+        (result, project) = ask(
+            session,
+            candidate,
+            &mut model,
+            "rewrite",
+            &mut |model| match &rewrite {
+                None => conversation_messages(session.llm, &mut conversation, model),
+                Some(rewrite) => vec![rewrite.clone()],
+            },
+            &schema,
+        )?;
+        if *VERBOSE.lock().unwrap() {
+            println!("{:#?}", project);
+        }
+        create_project(lang, sandbox, &project);
+        build_res = build_tool(lang, sandbox, &project.build_command, cache);
+        test_res = build_tool(lang, sandbox, &project.test_command, cache);
+        Best::update(best, &project, build_res.0, test_res.0);
+        failed_rewrites += 1;
+    }
+    if build_res.0 && test_res.0 {
+        let _ = session.winner.compare_exchange(
+            0,
+            candidate.number,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+    Ok(())
+}
 
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
+// Sends the request again to the next models of the fallback chain while the answer
+// has no code at all. `messages` gives the request for the model at a position.
+fn ask(
+    session: &Session,
+    candidate: &Candidate,
+    model: &mut usize,
+    phase: &str,
+    messages: &mut dyn FnMut(usize) -> Vec<ChatMessage>,
+    schema: &Option<serde_json::Value>,
+) -> Result<(String, Project), LlmError> {
+    let json_output = session.options.json_output;
+    loop {
+        let result = session.llm.chat_with(
+            *model,
+            phase,
+            messages(*model),
+            schema.clone(),
+            candidate.sampling,
+            session.cache,
+        )?;
+        let project = parse_project(&result, session.lang, json_output);
+        if !project.solution_code.trim().is_empty() {
+            return Ok((result, project));
+        }
+        println!("LLM answer has no code");
+        if !escalate(session, candidate, model) {
+            return Ok((result, project));
+        }
+    }
+}
 
-                    create_project_typescript(&project);
+// The history is trimmed for the model it goes to, which changes with every escalation
+fn conversation_messages(
+    llm: &LLMApi,
+    conversation: &mut Conversation,
+    model: usize,
+) -> Vec<ChatMessage> {
+    conversation.set_model(history_tokens(llm, model), llm.estimator(model));
+    conversation.messages()
+}
 
-                    build_res = build_tool(lang, &project.build_command, cache);
+// What is left of the window of the model after its answer
+fn history_tokens(llm: &LLMApi, model: usize) -> usize {
+    llm.context_window(model)
+        .map_or(MAX_HISTORY_TOKENS, |window| {
+            MAX_HISTORY_TOKENS.min(window.saturating_sub(MAX_TOKENS as usize))
+        })
+}
 
-                    test_res = build_tool(lang, &project.test_command, cache);
+// Each of the build and test outputs gets an eighth of the window
+fn output_tokens(context_window: Option<usize>) -> usize {
+    context_window.map_or(MAX_OUTPUT_TOKENS, |window| {
+        (window / 8).min(MAX_OUTPUT_TOKENS)
+    })
+}
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
+fn escalate(session: &Session, candidate: &Candidate, model: &mut usize) -> bool {
+    match session.llm.escalate(*model) {
+        Some((next, name)) => {
+            *model = next;
+            match session.options.candidates {
+                0 | 1 => println!("Escalate to model {}", name),
+                _ => println!("Candidate {} escalates to model {}", candidate.number, name),
             }
+            true
         }
-        Lang::Php => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Php);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_php(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Php);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_php(&project);
+        None => false,
+    }
+}
 
-                    build_res = build_tool(lang, &project.build_command, cache);
+// Markdown parsing stays the fallback when a model ignores the JSON format
+fn parse_project(result: &str, lang: &Lang, json_output: bool) -> Project {
+    if json_output {
+        match LLMResponse::parse_json_response(result, lang.clone()) {
+            Some(project) => return project,
+            None => println!("LLM answer is not the expected JSON, parse it as markdown"),
+        }
+    }
+    LLMResponse::parse_llm_response(result, lang.clone())
+}
 
-                    test_res = build_tool(lang, &project.test_command, cache);
+fn supported(lang: &Lang) -> bool {
+    matches!(
+        lang,
+        Lang::Rust
+            | Lang::Java
+            | Lang::Scala
+            | Lang::Swift
+            | Lang::Kotlin
+            | Lang::Python
+            | Lang::JavaScript
+            | Lang::TypeScript
+            | Lang::Php
+    )
+}
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
-        }
+fn create_project(lang: &Lang, sandbox: &str, project: &Project) {
+    match lang {
+        Lang::Rust => create_project_rust(lang, sandbox, project),
+        Lang::Java => create_project_java(sandbox, project),
+        Lang::Scala => create_project_scala(sandbox, project),
+        Lang::Swift => create_project_swift(sandbox, project),
+        Lang::Kotlin => create_project_kotlin(sandbox, project),
+        Lang::Python => create_project_python(sandbox, project),
+        Lang::JavaScript => create_project_javascript(sandbox, project),
+        Lang::TypeScript => create_project_typescript(sandbox, project),
+        Lang::Php => create_project_php(sandbox, project),
+        _ => panic!("Unknown lang: {}", lang),
+    }
+}
 
-        &_ => {
-            panic!("Unknown lang: {}", lang);
-        }
+// Order of placeholders of the "rewrite" prompt in prompt/<lang>.txt
+fn rewrite_params(
+    lang: &Lang,
+    project: Project,
+    build_output: String,
+    test_output: String,
+    question: &str,
+) -> Vec<String> {
+    match lang {
+        Lang::Rust => vec![
+            project.dependencies,
+            project.solution_code,
+            project.build_command,
+            build_output,
+            project.test_code,
+            test_output,
+            question.to_string(),
+        ],
+        Lang::TypeScript => vec![
+            project.dependencies,
+            project.additional_config[0].clone(),
+            project.solution_code,
+            project.test_code,
+            project.build_command,
+            build_output,
+            project.test_command,
+            test_output,
+            question.to_string(),
+        ],
+        _ => vec![
+            project.dependencies,
+            project.solution_code,
+            project.test_code,
+            project.build_command,
+            build_output,
+            project.test_command,
+            test_output,
+            question.to_string(),
+        ],
     }
 }
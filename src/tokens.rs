@@ -0,0 +1,98 @@
+/// Rough token counter of a model, good enough to keep prompts inside its context window
+/// without shipping the tokenizers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimator {
+    chars_per_token: f32,
+}
+
+impl Estimator {
+    /// `model` is a backend name like "openai:gpt-4o" or "ollama:qwen2.5-coder:7b".
+    pub fn for_model(model: &str) -> Estimator {
+        // Measured on source code: large vocabularies of hosted models pack more
+        // characters in a token than the ones of small local models
+        let hosted_openai = ["openai:gpt-", "openai:o1", "openai:o3", "openai:o4"];
+        let chars_per_token = if hosted_openai.iter().any(|prefix| model.starts_with(prefix)) {
+            4.0
+        } else if model.starts_with("anthropic:") {
+            3.5
+        } else {
+            3.0
+        };
+        Estimator { chars_per_token }
+    }
+
+    pub fn tokens(&self, text: &str) -> usize {
+        (text.chars().count() as f32 / self.chars_per_token).ceil() as usize
+    }
+
+    fn max_chars(&self, max_tokens: usize) -> usize {
+        (max_tokens as f32 * self.chars_per_token) as usize
+    }
+
+    /// Keeps the beginning and the end of long compiler output: the first errors and the
+    /// summary are the most useful parts.
+    pub fn trim_middle(&self, text: &str, max_tokens: usize) -> String {
+        let max_chars = self.max_chars(max_tokens);
+        let chars = text.chars().collect::<Vec<_>>();
+        if chars.len() <= max_chars {
+            return text.to_string();
+        }
+        let head = max_chars * 2 / 3;
+        let tail = max_chars - head;
+        let omitted = text_lines(&chars[head..chars.len() - tail]);
+        format!(
+            "{}\n... {} lines omitted ...\n{}",
+            chars[..head].iter().collect::<String>(),
+            omitted,
+            chars[chars.len() - tail..].iter().collect::<String>()
+        )
+    }
+
+    /// Keeps the beginning of a long file.
+    pub fn truncate(&self, text: &str, max_tokens: usize) -> String {
+        let max_chars = self.max_chars(max_tokens);
+        let chars = text.chars().collect::<Vec<_>>();
+        if chars.len() <= max_chars {
+            return text.to_string();
+        }
+        format!(
+            "{}\n... {} more lines truncated ...\n",
+            chars[..max_chars].iter().collect::<String>(),
+            text_lines(&chars[max_chars..])
+        )
+    }
+}
+
+fn text_lines(chars: &[char]) -> usize {
+    chars.iter().filter(|c| **c == '\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimator() {
+        let openai = Estimator::for_model("openai:gpt-4o");
+        let ollama = Estimator::for_model("ollama:qwen2.5-coder:7b");
+        assert_eq!(openai.tokens("fn solution() {}"), 4);
+        assert_eq!(ollama.tokens("fn solution() {}"), 6);
+        assert_eq!(Estimator::for_model("openai:o3-mini"), openai);
+        assert_eq!(Estimator::for_model("openai:openchat"), ollama);
+
+        let output = (0..100)
+            .map(|i| format!("error line {}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let trimmed = ollama.trim_middle(&output, 30);
+        assert!(trimmed.starts_with("error line 0\n"));
+        assert!(trimmed.ends_with("error line 99"));
+        assert!(trimmed.contains("lines omitted"));
+        assert!(ollama.tokens(&trimmed) < 40);
+        assert_eq!(ollama.trim_middle("short", 30), "short");
+
+        let truncated = ollama.truncate(&output, 30);
+        assert!(truncated.starts_with("error line 0\n"));
+        assert!(truncated.ends_with("more lines truncated ...\n"));
+    }
+}
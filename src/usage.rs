@@ -0,0 +1,298 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Price of a model in dollars per million tokens.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct UsageRecord {
+    /// Prompt name like "generate" or "rewrite", "ask" or "embedding".
+    pub phase: String,
+    pub model: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub latency: Duration,
+    pub cached: bool,
+}
+
+/// Hard limits of a session. A request that would go over one of them is not sent.
+#[derive(Debug)]
+pub struct Budget {
+    pub max_tokens: Option<i64>,
+    /// Dollars, needs a price table.
+    pub max_cost: Option<f64>,
+    pub max_time: Option<Duration>,
+    /// Time of the first LLM call, so waiting for user input does not count.
+    started: OnceLock<Instant>,
+}
+
+impl Budget {
+    pub fn new(
+        max_tokens: Option<i64>,
+        max_cost: Option<f64>,
+        max_time: Option<Duration>,
+    ) -> Budget {
+        Budget {
+            max_tokens,
+            max_cost,
+            max_time,
+            started: OnceLock::new(),
+        }
+    }
+
+    pub fn unlimited() -> Budget {
+        Budget::new(None, None, None)
+    }
+}
+
+/// Token counts and latency of every LLM call of the session.
+#[derive(Debug, Default)]
+pub struct UsageTracker {
+    records: Mutex<Vec<UsageRecord>>,
+    prices: HashMap<String, Price>,
+}
+
+impl UsageTracker {
+    pub fn new(prices: HashMap<String, Price>) -> UsageTracker {
+        UsageTracker {
+            records: Mutex::new(Vec::new()),
+            prices,
+        }
+    }
+
+    /// Reads a JSON price table: `{"gpt-4o-2024-08-06": {"input": 2.5, "output": 10.0}}`.
+    pub fn load_prices(file_name: &str) -> Result<HashMap<String, Price>, String> {
+        let content = std::fs::read_to_string(file_name)
+            .map_err(|err| format!("Can't read price table \"{}\": {}", file_name, err))?;
+        serde_json::from_str(&content)
+            .map_err(|err| format!("Wrong price table \"{}\": {}", file_name, err))
+    }
+
+    pub fn record(&self, record: UsageRecord) {
+        self.records.lock().unwrap().push(record);
+    }
+
+    pub fn records(&self) -> Vec<UsageRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    // Models are recorded as "provider:model", prices may use either form
+    fn price(&self, model: &str) -> Option<Price> {
+        self.prices.get(model).copied().or_else(|| {
+            let (_, name) = model.split_once(':')?;
+            self.prices.get(name).copied()
+        })
+    }
+
+    /// Models of `models` which have no price, a cost budget can't be kept with them.
+    pub fn unpriced(&self, models: &[String]) -> Vec<String> {
+        models
+            .iter()
+            .filter(|model| self.price(model).is_none())
+            .cloned()
+            .collect()
+    }
+
+    /// Estimated cost in dollars, None if no called model has a price.
+    pub fn cost(&self) -> Option<f64> {
+        let records = self.records.lock().unwrap();
+        let mut total = None;
+        for record in records.iter() {
+            if let Some(price) = self.price(&record.model) {
+                *total.get_or_insert(0.0) += (record.prompt_tokens as f64 * price.input
+                    + record.completion_tokens as f64 * price.output)
+                    / 1_000_000.0;
+            }
+        }
+        total
+    }
+
+    pub fn total_tokens(&self) -> i64 {
+        let records = self.records.lock().unwrap();
+        records
+            .iter()
+            .map(|r| r.prompt_tokens + r.completion_tokens)
+            .sum()
+    }
+
+    /// Checks whether a call to `model` stays within the budget even when it uses all
+    /// `completion_tokens` it may get.
+    pub fn check_budget(
+        &self,
+        budget: &Budget,
+        model: &str,
+        prompt_tokens: i64,
+        completion_tokens: i64,
+    ) -> Result<(), String> {
+        if let Some(max_time) = budget.max_time {
+            if budget.started.get_or_init(Instant::now).elapsed() >= max_time {
+                return Err(format!(
+                    "time budget of {} s is exhausted",
+                    max_time.as_secs()
+                ));
+            }
+        }
+        if let Some(max_tokens) = budget.max_tokens {
+            let tokens = self.total_tokens() + prompt_tokens + completion_tokens;
+            if tokens > max_tokens {
+                return Err(format!(
+                    "token budget would be exceeded: {} of {} tokens",
+                    tokens, max_tokens
+                ));
+            }
+        }
+        if let Some(max_cost) = budget.max_cost {
+            let next_cost = self.price(model).map_or(0.0, |price| {
+                (prompt_tokens as f64 * price.input + completion_tokens as f64 * price.output)
+                    / 1_000_000.0
+            });
+            let cost = self.cost().unwrap_or_default() + next_cost;
+            if cost > max_cost {
+                return Err(format!(
+                    "cost budget would be exceeded: ${:.4} of ${:.4}",
+                    cost, max_cost
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn summary(&self) -> String {
+        let records = self.records();
+        let mut phases: Vec<String> = Vec::new();
+        for record in &records {
+            if !phases.contains(&record.phase) {
+                phases.push(record.phase.clone());
+            }
+        }
+
+        let mut summary = String::new();
+        for phase in phases.iter().map(Some).chain([None]) {
+            let selected = records
+                .iter()
+                .filter(|r| phase.is_none_or(|phase| &r.phase == phase))
+                .collect::<Vec<_>>();
+            writeln!(
+                summary,
+                "{}: {} calls ({} cached), {} prompt + {} completion tokens, {:.1} s",
+                phase.map_or("Total", |phase| phase.as_str()),
+                selected.len(),
+                selected.iter().filter(|r| r.cached).count(),
+                selected.iter().map(|r| r.prompt_tokens).sum::<i64>(),
+                selected.iter().map(|r| r.completion_tokens).sum::<i64>(),
+                selected
+                    .iter()
+                    .map(|r| r.latency)
+                    .sum::<Duration>()
+                    .as_secs_f32(),
+            )
+            .unwrap();
+        }
+        if let Some(cost) = self.cost() {
+            writeln!(summary, "Estimated cost: ${:.4}", cost).unwrap();
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(phase: &str, prompt_tokens: i64, completion_tokens: i64) -> UsageRecord {
+        UsageRecord {
+            phase: phase.to_string(),
+            model: "openai:gpt-4o".to_string(),
+            prompt_tokens,
+            completion_tokens,
+            latency: Duration::from_millis(500),
+            cached: false,
+        }
+    }
+
+    #[test]
+    fn test_usage_summary() {
+        let prices = HashMap::from([(
+            "gpt-4o".to_string(),
+            Price {
+                input: 2.5,
+                output: 10.0,
+            },
+        )]);
+        let tracker = UsageTracker::new(prices);
+        tracker.record(record("generate", 1000, 500));
+        tracker.record(record("rewrite", 2000, 500));
+        tracker.record(record("rewrite", 3000, 1000));
+
+        assert_eq!(
+            tracker.cost(),
+            Some((6000.0 * 2.5 + 2000.0 * 10.0) / 1_000_000.0)
+        );
+        assert_eq!(
+            tracker.summary(),
+            "generate: 1 calls (0 cached), 1000 prompt + 500 completion tokens, 0.5 s\n\
+             rewrite: 2 calls (0 cached), 5000 prompt + 1500 completion tokens, 1.0 s\n\
+             Total: 3 calls (0 cached), 6000 prompt + 2000 completion tokens, 1.5 s\n\
+             Estimated cost: $0.0350\n"
+        );
+        assert_eq!(UsageTracker::default().cost(), None);
+    }
+
+    #[test]
+    fn test_check_budget() {
+        let prices = HashMap::from([(
+            "gpt-4o".to_string(),
+            Price {
+                input: 2.5,
+                output: 10.0,
+            },
+        )]);
+        let tracker = UsageTracker::new(prices);
+        tracker.record(record("generate", 1000, 500));
+
+        let budget = Budget::new(Some(2000), None, None);
+        assert!(tracker
+            .check_budget(&budget, "openai:gpt-4o", 500, 0)
+            .is_ok());
+        assert!(tracker
+            .check_budget(&budget, "openai:gpt-4o", 501, 0)
+            .is_err());
+        assert!(tracker
+            .check_budget(&budget, "openai:gpt-4o", 0, 501)
+            .is_err());
+
+        // $0.0075 is spent, 1000 prompt tokens more cost $0.0025
+        let budget = Budget::new(None, Some(0.01), None);
+        assert!(tracker
+            .check_budget(&budget, "openai:gpt-4o", 1000, 0)
+            .is_ok());
+        assert!(tracker
+            .check_budget(&budget, "openai:gpt-4o", 1001, 0)
+            .is_err());
+        // The longest possible answer is reserved: 200 tokens of it cost $0.002
+        assert!(tracker
+            .check_budget(&budget, "openai:gpt-4o", 200, 200)
+            .is_ok());
+        assert!(tracker
+            .check_budget(&budget, "openai:gpt-4o", 200, 201)
+            .is_err());
+
+        let budget = Budget::new(None, None, Some(Duration::ZERO));
+        assert!(tracker
+            .check_budget(&budget, "openai:gpt-4o", 0, 0)
+            .is_err());
+        assert!(tracker
+            .check_budget(&Budget::unlimited(), "openai:gpt-4o", 1_000_000, 0)
+            .is_ok());
+
+        let models = ["openai:gpt-4o", "ollama:qwen2.5-coder:7b"].map(String::from);
+        assert_eq!(tracker.unpriced(&models), vec!["ollama:qwen2.5-coder:7b"]);
+    }
+}